@@ -0,0 +1,65 @@
+// Compile time embedding of migration files. `embed_migrations!("migrations/workspace")` reads
+// every numbered `.sql` file in the directory, relative to the crate's Cargo.toml, and expands
+// to a `&'static [&'static str]` of their contents in order:
+//
+//     const WORKSPACE: Migration = Migration::new("workspace", embed_migrations!("migrations/workspace"));
+//
+// Files are named `<number>_<description>.sql`. Numbering must start at 0 or 1 and have no gaps
+// or duplicates, otherwise the macro fails to compile. Each file is embedded with include_str so
+// editing a file triggers a rebuild. Cargo doesn't know the macro read the directory though, so
+// adding a new file goes unnoticed and the build keeps the old list. Crates using the macro
+// should add a build script which prints `cargo:rerun-if-changed=<directory>` for each
+// directory, which rebuilds the crate whenever a file in it is added, removed or changed.
+
+use std::{env, fs, path::Path};
+
+use litrs::StringLit;
+use proc_macro::{TokenStream, TokenTree};
+use sqlez_migration_files::order_migration_files;
+
+#[proc_macro]
+pub fn embed_migrations(input: TokenStream) -> TokenStream {
+    match expand(input) {
+        Ok(output) => output,
+        Err(error) => format!("compile_error!({:?})", error).parse().unwrap(),
+    }
+}
+
+fn expand(input: TokenStream) -> Result<TokenStream, String> {
+    let directory = parse_path(input)?;
+    let manifest_dir =
+        env::var("CARGO_MANIFEST_DIR").map_err(|_| "CARGO_MANIFEST_DIR is not set".to_string())?;
+    let directory = Path::new(&manifest_dir).join(directory);
+
+    let entries = fs::read_dir(&directory)
+        .map_err(|error| format!("Could not read {}: {}", directory.display(), error))?;
+    let mut file_names = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|error| error.to_string())?;
+        file_names.push(entry.file_name().to_string_lossy().into_owned());
+    }
+
+    let ordered = order_migration_files(&file_names)?;
+    let includes = ordered
+        .iter()
+        .map(|file_name| format!("include_str!({:?})", directory.join(file_name)))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!("&[{}]", includes)
+        .parse()
+        .map_err(|error| format!("{:?}", error))
+}
+
+fn parse_path(input: TokenStream) -> Result<String, String> {
+    let mut tokens = input.into_iter();
+    let literal = match (tokens.next(), tokens.next()) {
+        (Some(TokenTree::Literal(literal)), None) => literal,
+        _ => return Err("embed_migrations! expects a single string literal path".to_string()),
+    };
+
+    // Parsed rather than trimmed of its quotes so that raw strings and escapes are handled
+    StringLit::parse(literal.to_string())
+        .map(|path| path.value().to_string())
+        .map_err(|_| "embed_migrations! expects a single string literal path".to_string())
+}
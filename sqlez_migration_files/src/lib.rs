@@ -0,0 +1,73 @@
+// Naming rules for directories of migration files, shared by `embed_migrations!` at compile
+// time and `Migration::load_dir` at runtime so both accept exactly the same directories.
+// Files are named `<number>_<description>.sql`, numbered from 0 or 1 with no gaps or
+// duplicates. Other files are ignored.
+
+/// Orders the `.sql` files in a migration directory by their number prefix, ignoring any
+/// other files. Errors if a file is missing its number, or the numbers skip or repeat
+pub fn order_migration_files(file_names: &[String]) -> Result<Vec<String>, String> {
+    let mut numbered = Vec::new();
+    for file_name in file_names {
+        let Some(stem) = file_name.strip_suffix(".sql") else {
+            continue;
+        };
+
+        let digits = stem
+            .find(|c: char| !c.is_ascii_digit())
+            .map_or(stem, |end| &stem[..end]);
+        let number = digits
+            .parse::<usize>()
+            .map_err(|_| format!("Migration file {} does not start with a number", file_name))?;
+        numbered.push((number, file_name.clone()));
+    }
+    numbered.sort();
+
+    let first = numbered.first().map_or(0, |(number, _)| *number).min(1);
+    for (expected, (number, file_name)) in (first..).zip(&numbered) {
+        if *number < expected {
+            return Err(format!(
+                "Migration number {} is used more than once: {}",
+                number, file_name
+            ));
+        }
+        if *number > expected {
+            return Err(format!(
+                "Migration number {} is missing before {}",
+                expected, file_name
+            ));
+        }
+    }
+
+    Ok(numbered
+        .into_iter()
+        .map(|(_, file_name)| file_name)
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::order_migration_files;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn files_are_ordered_by_number() {
+        assert_eq!(
+            order_migration_files(&names(&["0010_c.sql", "README.md", "2_b.sql", "1_a.sql"]))
+                .unwrap_err(),
+            "Migration number 3 is missing before 0010_c.sql"
+        );
+        assert_eq!(
+            order_migration_files(&names(&["0002_b.sql", "README.md", "0001_a.sql"])).unwrap(),
+            names(&["0001_a.sql", "0002_b.sql"])
+        );
+    }
+
+    #[test]
+    fn duplicate_and_unnumbered_files_are_rejected() {
+        assert!(order_migration_files(&names(&["0001_a.sql", "0001_b.sql"])).is_err());
+        assert!(order_migration_files(&names(&["init.sql"])).is_err());
+    }
+}
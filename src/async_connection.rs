@@ -0,0 +1,179 @@
+// Async wrapper over ThreadSafeConnection. Reads are handed to a small set of reader threads
+// owned by this struct, each using its own thread local connection. Writes go through the
+// ThreadSafeConnection write queue. Both return plain futures backed by oneshot channels, so
+// any executor can poll them without sqlez ever blocking the executor's threads.
+
+use std::{
+    future::Future,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use anyhow::{anyhow, Result};
+use futures::{channel::oneshot, FutureExt};
+
+use crate::{connection::Connection, thread_safe_connection::ThreadSafeConnection};
+
+type ReadJob = Box<dyn FnOnce(&ThreadSafeConnection) + Send>;
+
+pub struct AsyncConnection {
+    connection: ThreadSafeConnection,
+    read_queue: Sender<ReadJob>,
+}
+
+impl AsyncConnection {
+    /// Wraps the connection with a single reader thread. A write queue is added to the
+    /// connection if it doesn't already have one
+    pub fn new(connection: ThreadSafeConnection) -> Self {
+        Self::with_reader_threads(connection, 1)
+    }
+
+    /// Wraps the connection, spawning `reader_threads` threads to run read callbacks on
+    pub fn with_reader_threads(connection: ThreadSafeConnection, reader_threads: usize) -> Self {
+        let connection = connection.with_write_queue();
+        let (read_queue, receiver) = mpsc::channel::<ReadJob>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for index in 0..reader_threads.max(1) {
+            let connection = connection.clone();
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("sqlez reader {}", index))
+                .spawn(move || Self::run_reader(&connection, &receiver))
+                .expect("Could not spawn reader thread");
+        }
+
+        Self {
+            connection,
+            read_queue,
+        }
+    }
+
+    /// Runs the callback on one of the reader threads using that thread's connection
+    pub fn read<T, F>(&self, callback: F) -> impl Future<Output = Result<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let queued = self.read_queue.send(Box::new(move |connection| {
+            sender.send(connection.run_callback(callback)).ok();
+        }));
+
+        async move {
+            queued.map_err(|_| anyhow!("Reader threads exited unexpectedly"))?;
+            Self::flatten(receiver.await)
+        }
+    }
+
+    /// Runs the callback on the connection's write queue thread
+    pub fn write<T, F>(&self, callback: F) -> impl Future<Output = Result<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        self.connection.queue_write(Box::new(move |connection| {
+            sender.send(connection.run_callback(callback)).ok();
+        }));
+
+        receiver.map(Self::flatten)
+    }
+
+    pub fn thread_safe_connection(&self) -> &ThreadSafeConnection {
+        &self.connection
+    }
+
+    fn run_reader(connection: &ThreadSafeConnection, receiver: &Mutex<Receiver<ReadJob>>) {
+        loop {
+            // Only hold the lock while waiting so other readers can run jobs concurrently
+            let job = match receiver.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(_) => return,
+            };
+
+            match job {
+                Ok(job) => job(connection),
+                Err(_) => return,
+            }
+        }
+    }
+
+    fn flatten<T>(result: Result<Result<T>, oneshot::Canceled>) -> Result<T> {
+        result.map_err(|_| anyhow!("Database callback was dropped before completing"))?
+    }
+}
+
+impl Clone for AsyncConnection {
+    fn clone(&self) -> Self {
+        Self {
+            connection: self.connection.clone(),
+            read_queue: self.read_queue.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{executor::block_on, future::join_all};
+    use indoc::indoc;
+
+    use crate::{async_connection::AsyncConnection, thread_safe_connection::ThreadSafeConnection};
+
+    #[test]
+    fn reads_observe_queued_writes() {
+        let connection = AsyncConnection::with_reader_threads(
+            ThreadSafeConnection::new("reads_observe_queued_writes", false),
+            2,
+        );
+
+        block_on(async {
+            connection
+                .write(|connection| {
+                    connection.exec(indoc! {"
+                        CREATE TABLE test (
+                            value INTEGER
+                        );"})
+                })
+                .await
+                .unwrap();
+
+            join_all((0..4).map(|i| {
+                connection.write(move |connection| {
+                    connection
+                        .prepare("INSERT INTO test (value) VALUES (?)")?
+                        .bound(i)?
+                        .run()
+                })
+            }))
+            .await
+            .into_iter()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+
+            let count = connection
+                .read(|connection| {
+                    connection
+                        .prepare("SELECT COUNT(*) FROM test")?
+                        .row::<i32>()
+                })
+                .await
+                .unwrap();
+            assert_eq!(count, 4);
+        });
+    }
+
+    #[test]
+    fn errors_are_returned_from_futures() {
+        let connection =
+            AsyncConnection::new(ThreadSafeConnection::new("errors_are_returned", false));
+
+        let result = block_on(
+            connection.read(|connection| connection.prepare("SELECT * FROM missing")?.row::<i32>()),
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,379 @@
+// Online backups between two connections. A backup copies a fixed number of pages per step so
+// other connections can use the source in between, retries steps while the source is locked,
+// and reports its progress to a callback which can cancel it by returning false. Any attached
+// schema can be backed up into any schema of the destination.
+// `backup_to_path` and `restore_from_path` wrap this for files, checking the integrity of the
+// copy before anything is replaced.
+
+use std::{
+    ffi::CString,
+    fs,
+    path::Path,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use libsqlite3_sys::*;
+
+use crate::connection::Connection;
+
+const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Distinguishes the temporary files of backups running at the same time in this process
+static NEXT_BACKUP: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackupProgress {
+    /// Pages still to be copied
+    pub remaining: usize,
+    /// Pages in the source database
+    pub total: usize,
+}
+
+type ProgressFn<'a> = dyn FnMut(BackupProgress) -> bool + 'a;
+
+pub struct Backup<'a> {
+    source: &'a Connection,
+    destination: &'a Connection,
+    source_schema: String,
+    destination_schema: String,
+    pages_per_step: i32,
+    step_delay: Duration,
+    busy_timeout: Duration,
+    progress: Option<Box<ProgressFn<'a>>>,
+}
+
+impl Connection {
+    /// Starts configuring a backup of this connection's main schema into the destination's
+    /// main schema. Nothing is copied until `run` is called
+    pub fn backup_to<'a>(&'a self, destination: &'a Connection) -> Backup<'a> {
+        Backup {
+            source: self,
+            destination,
+            source_schema: "main".to_string(),
+            destination_schema: "main".to_string(),
+            pages_per_step: -1,
+            step_delay: Duration::ZERO,
+            busy_timeout: DEFAULT_BUSY_TIMEOUT,
+            progress: None,
+        }
+    }
+
+    /// Backs up the main database to a file. The backup is written to a temporary file next
+    /// to the path and integrity checked, then renamed over the path so an existing backup
+    /// is only replaced by a complete one
+    pub fn backup_to_path(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let temporary_path = path.with_file_name(format!(
+            "{}.{}-{}.tmp",
+            path.file_name()
+                .ok_or_else(|| anyhow!("Backup path {} has no file name", path.display()))?
+                .to_string_lossy(),
+            process::id(),
+            NEXT_BACKUP.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let result = self
+            .backup_to_temporary_path(&temporary_path)
+            .and_then(|_| {
+                fs::rename(&temporary_path, path)
+                    .with_context(|| format!("Could not move backup to {}", path.display()))
+            });
+        if result.is_err() {
+            fs::remove_file(&temporary_path).ok();
+        }
+        result
+    }
+
+    fn backup_to_temporary_path(&self, temporary_path: &Path) -> Result<()> {
+        fs::remove_file(temporary_path).ok();
+        let destination = Connection::open(path_str(temporary_path)?, true)?;
+        self.backup_main(&destination)?;
+        destination.integrity_check().context("Backup is corrupt")
+    }
+
+    /// Replaces the main database with the one in a file. The file is opened read only and
+    /// integrity checked first, and the copy happens in a single step so a failure leaves
+    /// this database as it was
+    pub fn restore_from_path(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if !path.is_file() {
+            return Err(anyhow!("No backup to restore at {}", path.display()));
+        }
+
+        let source = Connection::open_read_only(path_str(path)?)?;
+        source
+            .integrity_check()
+            .with_context(|| format!("Backup at {} is corrupt", path.display()))?;
+        source.backup_main(self)?;
+        self.integrity_check()
+    }
+}
+
+fn path_str(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or_else(|| anyhow!("Path {} is not valid unicode", path.display()))
+}
+
+impl<'a> Backup<'a> {
+    /// Sets the schemas to copy from and into, such as the name of an attached database
+    pub fn with_schemas(mut self, source_schema: &str, destination_schema: &str) -> Self {
+        self.source_schema = source_schema.to_string();
+        self.destination_schema = destination_schema.to_string();
+        self
+    }
+
+    /// Copies at most this many pages per step. By default every page is copied in one step
+    pub fn with_pages_per_step(mut self, pages_per_step: usize) -> Self {
+        self.pages_per_step = pages_per_step.clamp(1, i32::MAX as usize) as i32;
+        self
+    }
+
+    /// Sleeps between steps so other connections get a chance to use the source. Without a
+    /// delay the thread only yields
+    pub fn with_step_delay(mut self, step_delay: Duration) -> Self {
+        self.step_delay = step_delay;
+        self
+    }
+
+    /// How long to keep retrying a step while the source or destination is locked
+    pub fn with_busy_timeout(mut self, busy_timeout: Duration) -> Self {
+        self.busy_timeout = busy_timeout;
+        self
+    }
+
+    /// Called after every step. Returning false cancels the backup
+    pub fn with_progress(mut self, progress: impl FnMut(BackupProgress) -> bool + 'a) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Copies every page, returning an error if the backup fails, times out waiting on a
+    /// lock or is cancelled. A cancelled backup leaves the destination partially written
+    pub fn run(mut self) -> Result<()> {
+        let destination_schema = CString::new(self.destination_schema.as_str())?;
+        let source_schema = CString::new(self.source_schema.as_str())?;
+        let backup = unsafe {
+            sqlite3_backup_init(
+                self.destination.sqlite3,
+                destination_schema.as_ptr(),
+                self.source.sqlite3,
+                source_schema.as_ptr(),
+            )
+        };
+        if backup.is_null() {
+            self.destination.last_error()?;
+            return Err(anyhow!("Could not start backup"));
+        }
+
+        let result = self.step_until_done(backup);
+        let finish_code = unsafe { sqlite3_backup_finish(backup) };
+        result?;
+        if finish_code != SQLITE_OK {
+            self.destination.last_error()?;
+            return Err(anyhow!("Backup failed with code {}", finish_code));
+        }
+        Ok(())
+    }
+
+    fn step_until_done(&mut self, backup: *mut sqlite3_backup) -> Result<()> {
+        let mut blocked_since = None;
+        loop {
+            let code = unsafe { sqlite3_backup_step(backup, self.pages_per_step) };
+            match code {
+                SQLITE_DONE => {
+                    self.report_progress(backup);
+                    return Ok(());
+                }
+                SQLITE_OK => blocked_since = None,
+                SQLITE_BUSY | SQLITE_LOCKED => {
+                    let blocked_since = *blocked_since.get_or_insert_with(Instant::now);
+                    if blocked_since.elapsed() >= self.busy_timeout {
+                        return Err(anyhow!(
+                            "Backup timed out waiting for a lock on the database"
+                        ));
+                    }
+                }
+                _ => return Err(anyhow!("Backup step failed with code {}", code)),
+            }
+
+            if !self.report_progress(backup) {
+                return Err(anyhow!("Backup was cancelled"));
+            }
+
+            if self.step_delay.is_zero() {
+                thread::yield_now();
+            } else {
+                thread::sleep(self.step_delay);
+            }
+        }
+    }
+
+    fn report_progress(&mut self, backup: *mut sqlite3_backup) -> bool {
+        let Some(progress) = &mut self.progress else {
+            return true;
+        };
+
+        let (remaining, total) = unsafe {
+            (
+                sqlite3_backup_remaining(backup),
+                sqlite3_backup_pagecount(backup),
+            )
+        };
+        progress(BackupProgress {
+            remaining: remaining.max(0) as usize,
+            total: total.max(0) as usize,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, thread};
+
+    use indoc::indoc;
+
+    use crate::{backup::BackupProgress, connection::Connection, test_support::TempDir};
+
+    fn fill(connection: &Connection) {
+        connection
+            .exec(indoc! {"
+                CREATE TABLE test (value TEXT);
+                WITH RECURSIVE numbers(n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM numbers LIMIT 200)
+                INSERT INTO test (value) SELECT printf('%.500d', n) FROM numbers;"})
+            .unwrap();
+    }
+
+    #[test]
+    fn backup_copies_incrementally_and_reports_progress() {
+        let source = Connection::open_memory("backup_copies_incrementally_source");
+        fill(&source);
+        let destination = Connection::open_memory("backup_copies_incrementally_destination");
+
+        let mut progress = Vec::new();
+        source
+            .backup_to(&destination)
+            .with_pages_per_step(5)
+            .with_progress(|step| {
+                progress.push(step);
+                true
+            })
+            .run()
+            .unwrap();
+
+        assert!(progress.len() > 2);
+        assert!(progress
+            .windows(2)
+            .all(|steps| steps[1].remaining < steps[0].remaining));
+        assert_eq!(
+            progress.last(),
+            Some(&BackupProgress {
+                remaining: 0,
+                total: progress[0].total
+            })
+        );
+        assert_eq!(
+            destination
+                .prepare("SELECT COUNT(*) FROM test")
+                .unwrap()
+                .row::<usize>()
+                .unwrap(),
+            200
+        );
+    }
+
+    #[test]
+    fn backups_can_be_cancelled_and_use_attached_schemas() {
+        let source = Connection::open_memory("backups_can_be_cancelled_source");
+        source.exec("ATTACH ':memory:' AS other").unwrap();
+        source.exec("CREATE TABLE other.attached (a TEXT)").unwrap();
+        fill(&source);
+        let destination = Connection::open_memory("backups_can_be_cancelled_destination");
+
+        let mut steps = 0;
+        let error = source
+            .backup_to(&destination)
+            .with_pages_per_step(1)
+            .with_progress(|_| {
+                steps += 1;
+                steps < 3
+            })
+            .run()
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Backup was cancelled");
+        assert_eq!(steps, 3);
+
+        source
+            .backup_to(&destination)
+            .with_schemas("other", "main")
+            .run()
+            .unwrap();
+        destination.exec("SELECT a FROM attached").unwrap();
+        assert!(destination.exec("SELECT value FROM test").is_err());
+    }
+
+    #[test]
+    fn backups_round_trip_through_files() {
+        let directory = TempDir::new("backup_paths");
+        let path = directory.join("backup.sqlite");
+
+        let source = Connection::open_memory("backups_round_trip_through_files_source");
+        fill(&source);
+        source.backup_to_path(&path).unwrap();
+        source.exec("DELETE FROM test").unwrap();
+        source.backup_to_path(&path).unwrap();
+        assert_eq!(fs::read_dir(directory.path()).unwrap().count(), 1);
+
+        // Each backup writes its own temporary file, so concurrent ones don't clobber each other
+        let backups = (0..4)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    let source = Connection::open_memory("backups_round_trip_through_files_source");
+                    source.backup_to_path(&path)
+                })
+            })
+            .collect::<Vec<_>>();
+        for backup in backups {
+            backup.join().unwrap().unwrap();
+        }
+        assert_eq!(fs::read_dir(directory.path()).unwrap().count(), 1);
+
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&path, permissions).unwrap();
+
+        let restored = Connection::open_memory("backups_round_trip_through_files_restored");
+        fill(&restored);
+        restored.restore_from_path(&path).unwrap();
+        assert_eq!(
+            restored
+                .prepare("SELECT COUNT(*) FROM test")
+                .unwrap()
+                .row::<usize>()
+                .unwrap(),
+            0
+        );
+
+        fs::remove_file(&path).unwrap();
+        fs::write(&path, vec![7; 8192]).unwrap();
+        source
+            .exec("INSERT INTO test (value) VALUES ('kept')")
+            .unwrap();
+        assert!(source.restore_from_path(&path).is_err());
+        assert!(source
+            .restore_from_path(directory.join("missing.sqlite"))
+            .is_err());
+        assert_eq!(
+            source
+                .prepare("SELECT COUNT(*) FROM test")
+                .unwrap()
+                .row::<usize>()
+                .unwrap(),
+            1
+        );
+    }
+}
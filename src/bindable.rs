@@ -201,8 +201,8 @@ impl<T: Column + Default + Copy, const COUNT: usize> Column for [T; COUNT] {
     fn column(statement: &mut Statement, start_index: i32) -> Result<(Self, i32)> {
         let mut array = [Default::default(); COUNT];
         let mut current_index = start_index;
-        for i in 0..COUNT {
-            (array[i], current_index) = T::column(statement, current_index)?;
+        for value in array.iter_mut() {
+            (*value, current_index) = T::column(statement, current_index)?;
         }
         Ok((array, current_index))
     }
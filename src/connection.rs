@@ -1,35 +1,53 @@
 use std::{
+    cell::RefCell,
     ffi::{CStr, CString},
     marker::PhantomData,
+    ptr,
 };
 
 use anyhow::{anyhow, Result};
 use libsqlite3_sys::*;
 
-use crate::statement::Statement;
+use crate::{statement::Statement, wal::WalHook};
 
 pub struct Connection {
     pub(crate) sqlite3: *mut sqlite3,
     persistent: bool,
+    /// Kept alive for as long as sqlite may call it. Double boxed so sqlite can be handed a
+    /// thin pointer
+    pub(crate) wal_hook: RefCell<Option<Box<Box<WalHook>>>>,
     phantom: PhantomData<sqlite3>,
 }
 unsafe impl Send for Connection {}
 
 impl Connection {
-    fn open(uri: &str, persistent: bool) -> Result<Self> {
+    pub(crate) fn open(uri: &str, persistent: bool) -> Result<Self> {
+        Self::open_with_flags(
+            uri,
+            persistent,
+            SQLITE_OPEN_CREATE | SQLITE_OPEN_NOMUTEX | SQLITE_OPEN_READWRITE,
+        )
+    }
+
+    /// Opens an existing database without taking write access to it
+    pub(crate) fn open_read_only(uri: &str) -> Result<Self> {
+        Self::open_with_flags(uri, true, SQLITE_OPEN_NOMUTEX | SQLITE_OPEN_READONLY)
+    }
+
+    fn open_with_flags(uri: &str, persistent: bool, flags: i32) -> Result<Self> {
         let mut connection = Self {
-            sqlite3: 0 as *mut _,
+            sqlite3: ptr::null_mut(),
             persistent,
+            wal_hook: RefCell::new(None),
             phantom: PhantomData,
         };
 
-        let flags = SQLITE_OPEN_CREATE | SQLITE_OPEN_NOMUTEX | SQLITE_OPEN_READWRITE;
         unsafe {
             sqlite3_open_v2(
                 CString::new(uri)?.as_ptr(),
                 &mut connection.sqlite3,
                 flags,
-                0 as *const _,
+                ptr::null(),
             );
 
             connection.last_error()?;
@@ -53,38 +71,54 @@ impl Connection {
         self.persistent
     }
 
+    /// Whether the connection is inside an explicit transaction or savepoint
+    pub fn in_transaction(&self) -> bool {
+        unsafe { sqlite3_get_autocommit(self.sqlite3) == 0 }
+    }
+
     pub fn exec(&self, query: impl AsRef<str>) -> Result<()> {
         unsafe {
             sqlite3_exec(
                 self.sqlite3,
                 CString::new(query.as_ref())?.as_ptr(),
                 None,
-                0 as *mut _,
-                0 as *mut _,
+                ptr::null_mut(),
+                ptr::null_mut(),
             );
             self.last_error()?;
         }
         Ok(())
     }
 
-    pub fn prepare<T: AsRef<str>>(&self, query: T) -> Result<Statement> {
-        Statement::prepare(&self, query)
+    pub fn prepare<T: AsRef<str>>(&self, query: T) -> Result<Statement<'_>> {
+        Statement::prepare(self, query)
     }
 
+    /// Copies the main database into the destination's main database in a single step. Use
+    /// `backup_to` for incremental backups
     pub fn backup_main(&self, destination: &Connection) -> Result<()> {
+        self.backup_to(destination).run()
+    }
+
+    /// Returns the path of the file backing the main database, or None for in memory
+    /// databases
+    pub(crate) fn main_file_path(&self) -> Option<String> {
         unsafe {
-            let backup = sqlite3_backup_init(
-                destination.sqlite3,
-                CString::new("main")?.as_ptr(),
-                self.sqlite3,
-                CString::new("main")?.as_ptr(),
-            );
-            sqlite3_backup_step(backup, -1);
-            sqlite3_backup_finish(backup);
-            destination.last_error()
+            let path = sqlite3_db_filename(self.sqlite3, CString::new("main").ok()?.as_ptr());
+            if path.is_null() {
+                return None;
+            }
+
+            let path = CStr::from_ptr(path).to_string_lossy().into_owned();
+            (!path.is_empty()).then_some(path)
         }
     }
 
+    /// Whether the last call failed because another connection holds a conflicting lock
+    pub(crate) fn is_busy(&self) -> bool {
+        unsafe { sqlite3_errcode(self.sqlite3) & 0xff == SQLITE_BUSY }
+    }
+
     pub(crate) fn last_error(&self) -> Result<()> {
         const NON_ERROR_CODES: &[i32] = &[SQLITE_OK, SQLITE_ROW];
         unsafe {
@@ -0,0 +1,352 @@
+// Bounded alternative to ThreadSafeConnection. Instead of one connection per thread that lives
+// as long as the ThreadSafeConnection, connections are checked out of a pool with a fixed
+// maximum size and returned when the guard drops. Connections that sit idle longer than the
+// idle timeout are closed the next time the pool is touched, except that the last connection
+// to an in memory database is kept open so the database isn't destroyed.
+
+use std::{
+    ops::Deref,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{connection::Connection, thread_safe_connection::Initializer};
+
+const DEFAULT_MAX_SIZE: usize = 4;
+
+pub struct ConnectionPool {
+    uri: Arc<str>,
+    persistent: bool,
+    initializers: Vec<Initializer>,
+    max_size: usize,
+    idle_timeout: Option<Duration>,
+    shared: Arc<PoolShared>,
+}
+
+#[derive(Default)]
+struct PoolShared {
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+#[derive(Default)]
+struct PoolState {
+    idle: Vec<IdleConnection>,
+    open: usize,
+}
+
+struct IdleConnection {
+    connection: Connection,
+    returned_at: Instant,
+}
+
+pub struct PooledConnection {
+    connection: Option<Connection>,
+    shared: Arc<PoolShared>,
+}
+
+impl ConnectionPool {
+    pub fn new(uri: &str, persistent: bool) -> Self {
+        Self {
+            uri: Arc::from(uri),
+            persistent,
+            initializers: Vec::new(),
+            max_size: DEFAULT_MAX_SIZE,
+            idle_timeout: None,
+            shared: Default::default(),
+        }
+    }
+
+    /// Sets the maximum number of connections the pool will have open at once
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size.max(1);
+        self
+    }
+
+    /// Sets how long a returned connection may sit unused before the pool closes it
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Adds a query to run every time a connection is opened
+    pub fn with_initialize_query(self, initialize_query: &'static str) -> Self {
+        self.with_initializer(Initializer::Query(initialize_query))
+    }
+
+    /// Adds an initializer to run every time a connection is opened
+    pub fn with_initializer(mut self, initializer: Initializer) -> Self {
+        self.initializers.push(initializer);
+        self
+    }
+
+    /// Checks out a connection, blocking until one is returned if the pool is at its
+    /// maximum size
+    pub fn checkout(&self) -> Result<PooledConnection> {
+        let mut state = self.lock_state()?;
+        loop {
+            self.close_expired(&mut state);
+
+            if let Some(idle) = state.idle.pop() {
+                return Ok(self.guard(idle.connection));
+            }
+
+            if state.open < self.max_size {
+                return self.open_reserved(state);
+            }
+
+            state = self
+                .shared
+                .returned
+                .wait(state)
+                .map_err(|_| anyhow!("Connection pool lock poisoned"))?;
+        }
+    }
+
+    /// Checks out a connection if one is idle or the pool has room to open another.
+    /// Returns None instead of blocking when the pool is exhausted
+    pub fn try_checkout(&self) -> Result<Option<PooledConnection>> {
+        let mut state = self.lock_state()?;
+        self.close_expired(&mut state);
+
+        if let Some(idle) = state.idle.pop() {
+            return Ok(Some(self.guard(idle.connection)));
+        }
+
+        if state.open < self.max_size {
+            return self.open_reserved(state).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    /// Closes any idle connections which have passed the idle timeout
+    pub fn close_idle(&self) -> Result<()> {
+        let mut state = self.lock_state()?;
+        self.close_expired(&mut state);
+        Ok(())
+    }
+
+    /// The number of connections currently open, both idle and checked out
+    pub fn open_connections(&self) -> usize {
+        self.shared
+            .state
+            .lock()
+            .map(|state| state.open)
+            .unwrap_or_default()
+    }
+
+    /// The number of open connections waiting in the pool to be checked out
+    pub fn idle_connections(&self) -> usize {
+        self.shared
+            .state
+            .lock()
+            .map(|state| state.idle.len())
+            .unwrap_or_default()
+    }
+
+    fn lock_state(&self) -> Result<MutexGuard<'_, PoolState>> {
+        self.shared
+            .state
+            .lock()
+            .map_err(|_| anyhow!("Connection pool lock poisoned"))
+    }
+
+    fn close_expired(&self, state: &mut PoolState) {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return;
+        };
+
+        // Idle connections are ordered by when they were returned, so expired ones come first
+        let mut expired = state
+            .idle
+            .iter()
+            .take_while(|idle| idle.returned_at.elapsed() >= idle_timeout)
+            .count();
+        // Closing the last connection to a shared memory database would destroy it. This
+        // includes file databases which fell back to memory because the file couldn't be opened
+        if expired == state.open && state.idle.iter().any(|idle| !idle.connection.persistent()) {
+            expired -= 1;
+        }
+
+        state.idle.drain(..expired);
+        state.open -= expired;
+    }
+
+    /// Reserves a slot in the pool and opens a connection for it outside of the lock.
+    /// The slot is released again if opening fails
+    fn open_reserved(&self, mut state: MutexGuard<PoolState>) -> Result<PooledConnection> {
+        state.open += 1;
+        drop(state);
+
+        match self.open_connection() {
+            Ok(connection) => Ok(self.guard(connection)),
+            Err(error) => {
+                if let Ok(mut state) = self.shared.state.lock() {
+                    state.open -= 1;
+                }
+                self.shared.returned.notify_one();
+                Err(error)
+            }
+        }
+    }
+
+    fn open_connection(&self) -> Result<Connection> {
+        let connection = if self.persistent {
+            Connection::open_file(self.uri.as_ref())
+        } else {
+            Connection::open_memory(self.uri.as_ref())
+        };
+
+        for initializer in &self.initializers {
+            initializer.run(&connection)?;
+        }
+
+        Ok(connection)
+    }
+
+    fn guard(&self, connection: Connection) -> PooledConnection {
+        PooledConnection {
+            connection: Some(connection),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Clone for ConnectionPool {
+    fn clone(&self) -> Self {
+        Self {
+            uri: self.uri.clone(),
+            persistent: self.persistent,
+            initializers: self.initializers.clone(),
+            max_size: self.max_size,
+            idle_timeout: self.idle_timeout,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Self::Target {
+        self.connection
+            .as_ref()
+            .expect("Pooled connection used after return")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            // Otherwise the next checkout would continue this transaction
+            if connection.in_transaction() {
+                connection.exec("ROLLBACK").ok();
+            }
+
+            if let Ok(mut state) = self.shared.state.lock() {
+                if connection.in_transaction() {
+                    state.open -= 1;
+                } else {
+                    state.idle.push(IdleConnection {
+                        connection,
+                        returned_at: Instant::now(),
+                    });
+                }
+            }
+            self.shared.returned.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{thread, time::Duration};
+
+    use crate::connection_pool::ConnectionPool;
+
+    #[test]
+    fn pool_never_exceeds_max_size() {
+        let pool = ConnectionPool::new("pool_never_exceeds_max_size", false).with_max_size(2);
+
+        let first = pool.checkout().unwrap();
+        let second = pool.checkout().unwrap();
+        assert!(pool.try_checkout().unwrap().is_none());
+        assert_eq!(pool.open_connections(), 2);
+
+        let waiting = {
+            let pool = pool.clone();
+            thread::spawn(move || pool.checkout().map(|_| ()))
+        };
+        drop(first);
+        waiting.join().unwrap().unwrap();
+
+        drop(second);
+        assert_eq!(pool.open_connections(), 2);
+        assert_eq!(pool.idle_connections(), 2);
+    }
+
+    #[test]
+    fn idle_connections_are_closed_after_timeout() {
+        let pool = ConnectionPool::new("idle_connections_are_closed", false)
+            .with_idle_timeout(Duration::from_millis(10));
+
+        let first = pool.checkout().unwrap();
+        first.exec("CREATE TABLE test (a TEXT)").unwrap();
+        drop((first, pool.checkout().unwrap()));
+        assert_eq!(pool.open_connections(), 2);
+
+        thread::sleep(Duration::from_millis(20));
+        pool.close_idle().unwrap();
+        assert_eq!(pool.open_connections(), 1);
+        pool.checkout().unwrap().exec("SELECT a FROM test").unwrap();
+    }
+
+    #[test]
+    fn open_transactions_are_rolled_back_on_return() {
+        let pool = ConnectionPool::new("open_transactions_are_rolled_back", false).with_max_size(1);
+        pool.checkout()
+            .unwrap()
+            .exec("CREATE TABLE test (a TEXT)")
+            .unwrap();
+
+        let connection = pool.checkout().unwrap();
+        connection
+            .exec("BEGIN; INSERT INTO test (a) VALUES ('rolled back')")
+            .unwrap();
+        drop(connection);
+
+        let connection = pool.checkout().unwrap();
+        assert!(!connection.in_transaction());
+        assert_eq!(
+            connection
+                .prepare("SELECT COUNT(*) FROM test")
+                .unwrap()
+                .row::<usize>()
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn initialize_query_runs_on_open() {
+        let pool = ConnectionPool::new("pool_initialize_query_runs", false)
+            .with_initialize_query("PRAGMA foreign_keys = ON");
+        let connection = pool.checkout().unwrap();
+        assert_eq!(
+            connection
+                .prepare("PRAGMA foreign_keys")
+                .unwrap()
+                .row::<i32>()
+                .unwrap(),
+            1
+        );
+
+        let failing = ConnectionPool::new("pool_initialize_query_fails", false)
+            .with_initialize_query("NOT VALID SQL");
+        assert!(failing.checkout().is_err());
+        assert_eq!(failing.open_connections(), 0);
+    }
+}
@@ -0,0 +1,250 @@
+// Domains group the tables owned by a single module along with the migrations which create
+// them. A Db is a ThreadSafeConnection typed by the domains it was opened with, so modules
+// can add query methods to `Db<TheirDomain>` and only the domains listed in the type have
+// their migrations run. A Db opened with several domains can be narrowed to any one of them:
+//
+//     let db = Db::<(Workspace, Editor)>::open("db", true);
+//     let workspace_db = db.domain::<Workspace, _>();
+
+use std::{marker::PhantomData, ops::Deref};
+
+use anyhow::Result;
+
+use crate::{
+    connection::Connection,
+    migrations::Migration,
+    thread_safe_connection::{Initializer, ThreadSafeConnection},
+};
+
+pub trait Domain {
+    /// The migration which creates this domain's tables. Any step kind, dependency or
+    /// changed policy the migration is built with applies when the domain is migrated
+    fn migration() -> &'static Migration;
+
+    fn name() -> &'static str {
+        Self::migration().domain()
+    }
+}
+
+/// A set of domains whose migrations can be run together. Implemented for every Domain and
+/// for tuples of domains, which are migrated in order unless their dependencies say otherwise
+pub trait Migrator {
+    fn migrations() -> Vec<&'static Migration>;
+
+    fn migrate(connection: &Connection) -> Result<()> {
+        Migration::run_all(connection, &Self::migrations())
+    }
+}
+
+impl Migrator for () {
+    fn migrations() -> Vec<&'static Migration> {
+        Vec::new()
+    }
+}
+
+impl<D: Domain> Migrator for D {
+    fn migrations() -> Vec<&'static Migration> {
+        vec![D::migration()]
+    }
+}
+
+/// Marks that a Migrator includes the domain D. The Index parameter records where in the
+/// tuple D sits and is always inferred, it only exists to keep the impls from overlapping
+pub trait Contains<D: Domain, Index> {}
+
+pub struct Here;
+pub struct There<Index>(PhantomData<Index>);
+
+impl<D: Domain> Contains<D, Here> for D {}
+
+macro_rules! impl_tuple_domains {
+    ($(($domain:ident, $index:ty)),+) => {
+        impl<$($domain: Domain),+> Migrator for ($($domain,)+) {
+            fn migrations() -> Vec<&'static Migration> {
+                vec![$($domain::migration()),+]
+            }
+        }
+
+        impl_tuple_domains!(@contains [$($domain),+] $(($domain, $index)),+);
+    };
+    (@contains $all:tt $(($domain:ident, $index:ty)),+) => {
+        $(impl_tuple_domains!(@contains_one $all $domain, $index);)+
+    };
+    (@contains_one [$($all:ident),+] $domain:ident, $index:ty) => {
+        impl<$($all: Domain),+> Contains<$domain, $index> for ($($all,)+) {}
+    };
+}
+
+impl_tuple_domains!((D1, Here), (D2, There<Here>));
+impl_tuple_domains!((D1, Here), (D2, There<Here>), (D3, There<There<Here>>));
+impl_tuple_domains!(
+    (D1, Here),
+    (D2, There<Here>),
+    (D3, There<There<Here>>),
+    (D4, There<There<There<Here>>>)
+);
+
+pub struct Db<M: Migrator> {
+    connection: ThreadSafeConnection,
+    _migrator: PhantomData<M>,
+}
+
+impl<M: Migrator> Db<M> {
+    pub fn open(uri: &str, persistent: bool) -> Self {
+        Self::new(ThreadSafeConnection::new(uri, persistent))
+    }
+
+    /// Wraps an already configured connection, adding the migrations for M to its setup.
+    /// The migrations run before the next connection is handed out, even if the connection
+    /// was already in use, in a single transaction unless a migration has non
+    /// transactional steps
+    pub fn new(connection: ThreadSafeConnection) -> Self {
+        let connection = connection.with_setup(Initializer::function(|connection| {
+            Migration::run_all_for_setup(connection, &M::migrations())
+        }));
+
+        Self {
+            connection,
+            _migrator: PhantomData,
+        }
+    }
+
+    /// Narrows this Db to a single one of its domains, sharing the same connections
+    pub fn domain<D: Domain, Index>(&self) -> Db<D>
+    where
+        M: Contains<D, Index>,
+    {
+        Db {
+            connection: self.connection.clone(),
+            _migrator: PhantomData,
+        }
+    }
+}
+
+impl<M: Migrator> Clone for Db<M> {
+    fn clone(&self) -> Self {
+        Self {
+            connection: self.connection.clone(),
+            _migrator: PhantomData,
+        }
+    }
+}
+
+impl<M: Migrator> Deref for Db<M> {
+    type Target = ThreadSafeConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.connection
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use indoc::indoc;
+
+    use crate::{
+        domain::{Db, Domain},
+        migrations::{Dependency, Migration},
+        thread_safe_connection::ThreadSafeConnection,
+    };
+
+    struct Workspace;
+
+    impl Domain for Workspace {
+        fn migration() -> &'static Migration {
+            const MIGRATION: Migration = Migration::new(
+                "workspace",
+                &[indoc! {"
+                    CREATE TABLE workspaces (
+                        workspace_id INTEGER PRIMARY KEY,
+                        path TEXT
+                    );"}],
+            );
+            &MIGRATION
+        }
+    }
+
+    impl Db<Workspace> {
+        fn add_workspace(&self, path: &str) -> Result<()> {
+            self.try_get()?
+                .prepare("INSERT INTO workspaces (path) VALUES (?)")?
+                .bound(path)?
+                .run()
+        }
+
+        fn workspace_paths(&self) -> Result<Vec<String>> {
+            self.try_get()?
+                .prepare("SELECT path FROM workspaces")?
+                .rows::<String>()
+        }
+    }
+
+    struct Editor;
+
+    impl Domain for Editor {
+        fn migration() -> &'static Migration {
+            const MIGRATION: Migration = Migration::new(
+                "editor",
+                &[indoc! {"
+                    CREATE TABLE editors (
+                        workspace_id INTEGER REFERENCES workspaces(workspace_id),
+                        path TEXT
+                    );"}],
+            )
+            .with_dependencies(&[Dependency::on("workspace")]);
+            &MIGRATION
+        }
+    }
+
+    #[test]
+    fn domains_are_migrated_together() {
+        let db = Db::<(Workspace, Editor)>::open("domains_are_migrated_together", false);
+
+        let workspace_db = db.domain::<Workspace, _>();
+        workspace_db.add_workspace("/some/path").unwrap();
+        assert_eq!(workspace_db.workspace_paths().unwrap(), vec!["/some/path"]);
+
+        assert_eq!(
+            db.try_get()
+                .unwrap()
+                .prepare("SELECT domain FROM migrations ORDER BY domain")
+                .unwrap()
+                .rows::<String>()
+                .unwrap(),
+            vec!["editor", "workspace"]
+        );
+    }
+
+    #[test]
+    fn connections_in_use_are_migrated_in_dependency_order() {
+        let connection = ThreadSafeConnection::new("connections_in_use_are_migrated", false);
+        connection.exec("CREATE TABLE unrelated (a TEXT)").unwrap();
+
+        let db = Db::<(Editor, Workspace)>::new(connection.clone());
+        db.domain::<Workspace, _>()
+            .add_workspace("/some/path")
+            .unwrap();
+        assert_eq!(
+            db.try_get()
+                .unwrap()
+                .prepare("SELECT domain FROM migrations ORDER BY rowid")
+                .unwrap()
+                .rows::<String>()
+                .unwrap(),
+            vec!["workspace", "editor"]
+        );
+    }
+
+    #[test]
+    fn only_listed_domains_are_migrated() {
+        let db = Db::<Workspace>::open("only_listed_domains_are_migrated", false);
+        db.add_workspace("/some/path").unwrap();
+
+        assert!(db
+            .try_get()
+            .unwrap()
+            .prepare("SELECT * FROM editors")
+            .is_err());
+    }
+}
@@ -1,6 +1,18 @@
+pub mod async_connection;
+pub mod backup;
 pub mod bindable;
 pub mod connection;
+pub mod connection_pool;
+pub mod domain;
 pub mod migrations;
+pub mod pragma;
 pub mod savepoint;
+pub mod schema;
+pub mod serialize;
 pub mod statement;
 pub mod thread_safe_connection;
+pub mod vacuum;
+pub mod wal;
+
+#[cfg(test)]
+mod test_support;
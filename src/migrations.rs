@@ -1,14 +1,67 @@
 // Migrations are constructed by domain, and stored in a table in the connection db with domain name,
 // effected tables, actual query text, and order.
-// If a migration is run and any of the query texts don't match, the app panics on startup (maybe fallback
-// to creating a new db?)
+// If a migration is run and any of the query texts don't match, the migration's ChangedPolicy decides
+// what happens. By default an error is returned, but the database can instead be backed up and
+// recreated, have the domain's objects dropped and reapplied, or be handed to a callback
 // Otherwise any missing migrations are run on the connection
+// Query texts are compared by a hash of their normalized form, so changes to whitespace or
+// comments don't count as a change. Steps which were intentionally changed can be marked as
+// amended, which updates the stored text instead of failing.
+// Steps are usually sql, but can also be rust functions for transformations which are awkward
+// in sql. Function steps are recorded by a stable name and version in place of query text.
+// Steps can optionally provide a down migration, which `rollback_to` runs in reverse order to
+// return the domain to an earlier step
+// Migrations can also be kept as numbered `.sql` files in a directory, either embedded at
+// compile time with `embed_migrations!` or read at runtime with `Migration::load_dir`
+// Running a migration takes the database's write lock, so several processes opening
+// the same file at once apply each step exactly once
+// Long lived domains can squash their early steps into a baseline schema with `squash`.
+// Databases which already applied the squashed steps skip the baseline, while new databases
+// apply the baseline in place of the squashed steps
+// Applications can also stamp their databases with `PRAGMA application_id` so that other
+// tools can tell who owns the file, and keep `PRAGMA user_version` as the total number of
+// steps applied across every domain
+// `test_upgrades` helps test migrations against databases left behind by every older version
 
-use anyhow::{anyhow, Result};
+use std::{
+    borrow::Cow,
+    fmt, fs,
+    path::Path,
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
 use indoc::{formatdoc, indoc};
 
 use crate::connection::Connection;
 
+pub use sqlez_macros::embed_migrations;
+use sqlez_migration_files::order_migration_files;
+
+/// Recorded for each squashed step after the first when a new database applies a baseline
+const SQUASHED_STEP: &str = "-- Squashed into the baseline at step 0";
+
+/// How long a migration waits for another connection to finish migrating the same database
+const MIGRATION_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the connection holding the migration lock row shows that it is still running
+const MIGRATION_LOCK_HEARTBEAT: Duration = Duration::from_secs(1);
+
+/// How long a lock row's heartbeat must stand still, while the database is free for it to be
+/// updated, before the row is assumed to have been left behind by a process which exited
+const MIGRATION_LOCK_STALE: Duration = Duration::from_secs(5);
+
+const MIGRATION_LOCK_POLL: Duration = Duration::from_millis(10);
+
+/// Distinguishes the lock rows claimed by connections in this process
+static NEXT_LOCK_OWNER: AtomicUsize = AtomicUsize::new(0);
+
 const MIGRATIONS_MIGRATION: Migration = Migration::new(
     "migrations",
     // The migrations migration must be infallable because it runs to completion
@@ -17,245 +70,2349 @@ const MIGRATIONS_MIGRATION: Migration = Migration::new(
         CREATE TABLE IF NOT EXISTS migrations (
             domain TEXT,
             step INTEGER,
-            migration TEXT
+            migration TEXT,
+            hash TEXT
         );
     "}],
 );
 
-pub struct Migration {
-    domain: &'static str,
-    migrations: &'static [&'static str],
-}
+/// A single step of a migration
+#[derive(Clone, Copy)]
+pub enum Step {
+    Sql(&'static str),
+    /// A rust function run against the connection. The name and version are stored in place
+    /// of the query text, so bumping the version counts as changing the step
+    Function {
+        name: &'static str,
+        version: u32,
+        function: fn(&Connection) -> Result<()>,
+    },
+}
+
+impl Step {
+    /// The text recorded in the migrations table for this step
+    pub fn text(&self) -> Cow<'static, str> {
+        match self {
+            Step::Sql(query) => Cow::Borrowed(query),
+            Step::Function { name, version, .. } => {
+                Cow::Owned(format!("rust:{}@{}", name, version))
+            }
+        }
+    }
+
+    fn execute(&self, connection: &Connection) -> Result<()> {
+        match self {
+            Step::Sql(query) => connection.exec(query),
+            Step::Function { function, .. } => function(connection),
+        }
+    }
+}
+
+/// What `Migration::run` does when a completed step's stored query no longer matches
+#[derive(Clone, Copy)]
+pub enum ChangedPolicy {
+    /// Return an error describing the change
+    Error,
+    /// Back up the database, drop every object in it, and run this migration from scratch.
+    /// Other domains are recreated the next time their migrations run
+    BackupAndRecreate,
+    /// Back up the database, then drop the objects created by this domain's stored steps and
+    /// apply every step again. Objects are found by scanning the stored steps for `CREATE`
+    /// statements
+    DropDomainAndReapply,
+    /// Back up the database and hand the change to a callback. The migration then runs as
+    /// normal, so the callback must resolve the change or the run still fails
+    Callback(fn(&Connection, &ChangedMigration) -> Result<()>),
+}
+
+/// A completed step whose stored query doesn't match the proposed one
+#[derive(Clone, Debug)]
+pub struct ChangedMigration {
+    pub domain: &'static str,
+    pub step: usize,
+    pub stored: String,
+    pub proposed: String,
+}
+
+impl fmt::Display for ChangedMigration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            formatdoc! {"
+                Migration changed for {} at step {}
+
+                Stored migration:
+                {}
+
+                Proposed migration:
+                {}", self.domain, self.step, self.stored, self.proposed}
+        )
+    }
+}
+
+/// A requirement that another domain be migrated before this one. If a step is given, the
+/// other domain must have completed at least that step
+#[derive(Clone, Copy, Debug)]
+pub struct Dependency {
+    pub domain: &'static str,
+    pub step: Option<usize>,
+}
+
+impl Dependency {
+    pub const fn on(domain: &'static str) -> Self {
+        Self { domain, step: None }
+    }
+
+    pub const fn at_step(domain: &'static str, step: usize) -> Self {
+        Self {
+            domain,
+            step: Some(step),
+        }
+    }
+}
+
+/// The state of each of a domain's steps in a database, as reported by `Migration::status`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MigrationStatus {
+    pub domain: &'static str,
+    /// Steps which have been applied and match the proposed step
+    pub applied: Vec<usize>,
+    /// Steps which have not been applied yet
+    pub pending: Vec<usize>,
+    /// Steps which have been applied but don't match the proposed step
+    pub mismatched: Vec<MismatchedStep>,
+    /// Steps recorded in the database which this migration doesn't know about
+    pub unknown: Vec<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MismatchedStep {
+    pub step: usize,
+    pub stored: String,
+    pub proposed: String,
+    /// Line diff from the stored to the proposed step, with removed lines prefixed by `-`,
+    /// added lines by `+` and unchanged lines by a space
+    pub diff: String,
+}
+
+/// The outcome of running a domain's pending steps in a transaction which was rolled back
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DryRun {
+    pub domain: &'static str,
+    /// Steps which would have been applied successfully
+    pub applied: Vec<usize>,
+    /// Completed steps which don't match, which a real run hands to the ChangedPolicy
+    pub mismatched: Vec<MismatchedStep>,
+    /// What a real run would do about the mismatched steps, if there are any
+    pub recovery: Option<Recovery>,
+    /// The error running the migration would have produced
+    pub error: Option<String>,
+}
+
+/// What a ChangedPolicy does about a mismatched step, as reported by a dry run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Recovery {
+    /// The run fails with an error describing the change
+    Fail,
+    /// The database is backed up, emptied and migrated from scratch
+    Recreate,
+    /// The database is backed up, then the domain's objects are dropped and every step is
+    /// applied again
+    Reapply,
+    /// The database is backed up and the change is handed to the callback. Callbacks aren't
+    /// called during a dry run, so none of the domain's steps are run either
+    Callback,
+}
+
+impl ChangedPolicy {
+    fn recovery(&self) -> Recovery {
+        match self {
+            ChangedPolicy::Error => Recovery::Fail,
+            ChangedPolicy::BackupAndRecreate => Recovery::Recreate,
+            ChangedPolicy::DropDomainAndReapply => Recovery::Reapply,
+            ChangedPolicy::Callback(_) => Recovery::Callback,
+        }
+    }
+}
+
+pub struct Migration {
+    domain: &'static str,
+    migrations: &'static [&'static str],
+    steps: &'static [Step],
+    down_migrations: &'static [Option<&'static str>],
+    non_transactional_steps: &'static [usize],
+    amended_steps: &'static [usize],
+    changed_policy: ChangedPolicy,
+    dependencies: &'static [Dependency],
+    baseline: Option<(&'static str, usize)>,
+}
+
+impl Migration {
+    pub const fn new(domain: &'static str, migrations: &'static [&'static str]) -> Self {
+        Self {
+            domain,
+            migrations,
+            steps: &[],
+            down_migrations: &[],
+            non_transactional_steps: &[],
+            amended_steps: &[],
+            changed_policy: ChangedPolicy::Error,
+            dependencies: &[],
+            baseline: None,
+        }
+    }
+
+    pub fn domain(&self) -> &'static str {
+        self.domain
+    }
+
+    /// Reads the numbered `.sql` files in a directory at runtime, for tools which don't know
+    /// their migrations at compile time. Files must be named `<number>_<description>.sql`
+    /// with numbering starting at 0 or 1 and no gaps or duplicates, matching
+    /// `embed_migrations!`. The file contents are leaked to satisfy the static lifetimes, so
+    /// this is meant to be called once per directory
+    pub fn load_dir(domain: &'static str, directory: impl AsRef<Path>) -> Result<Self> {
+        let directory = directory.as_ref();
+        let mut file_names = Vec::new();
+        for entry in fs::read_dir(directory)
+            .with_context(|| format!("Could not read {}", directory.display()))?
+        {
+            file_names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+
+        let mut migrations = Vec::new();
+        for file_name in order_migration_files(&file_names).map_err(|error| anyhow!(error))? {
+            let path = directory.join(file_name);
+            let migration = fs::read_to_string(&path)
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            migrations.push(&*Box::leak(migration.into_boxed_str()));
+        }
+
+        Ok(Self::new(domain, Box::leak(migrations.into_boxed_slice())))
+    }
+
+    /// Creates a migration from a mix of sql and function steps
+    pub const fn with_steps(domain: &'static str, steps: &'static [Step]) -> Self {
+        Self {
+            steps,
+            ..Self::new(domain, &[])
+        }
+    }
+
+    /// Sets the queries which undo each step, matched to the up migrations by index. Steps
+    /// without a down migration (None, or past the end of the slice) can't be rolled back
+    pub const fn with_down_migrations(
+        self,
+        down_migrations: &'static [Option<&'static str>],
+    ) -> Self {
+        Self {
+            down_migrations,
+            ..self
+        }
+    }
+
+    /// Marks steps which must run outside of a transaction, such as `VACUUM`. Every other
+    /// step runs inside a savepoint together with its record in the migrations table.
+    /// Non transactional steps are not atomic, and will still fail if the migration as a
+    /// whole is run inside a transaction
+    pub const fn with_non_transactional_steps(self, steps: &'static [usize]) -> Self {
+        Self {
+            non_transactional_steps: steps,
+            ..self
+        }
+    }
+
+    /// Marks steps whose query was intentionally changed after being released. When the
+    /// stored query for an amended step doesn't match, the stored query is replaced with the
+    /// new one rather than returning an error. The step is not run again
+    pub const fn with_amended_steps(self, steps: &'static [usize]) -> Self {
+        Self {
+            amended_steps: steps,
+            ..self
+        }
+    }
+
+    /// Sets what happens when a completed step's stored query doesn't match
+    pub const fn with_changed_policy(self, changed_policy: ChangedPolicy) -> Self {
+        Self {
+            changed_policy,
+            ..self
+        }
+    }
+
+    /// Declares domains which must be migrated before this one. Only checked by `run_all`
+    pub const fn with_dependencies(self, dependencies: &'static [Dependency]) -> Self {
+        Self {
+            dependencies,
+            ..self
+        }
+    }
+
+    /// Replaces steps `0..squashed_steps` with a baseline schema produced by `squash`. The
+    /// migration's own steps continue from step `squashed_steps`, and step indices passed to
+    /// the other builders still count the squashed steps. Databases which applied some but
+    /// not all of the squashed steps can no longer be migrated
+    pub const fn with_baseline(self, baseline: &'static str, squashed_steps: usize) -> Self {
+        Self {
+            baseline: Some((baseline, squashed_steps)),
+            ..self
+        }
+    }
+
+    /// Applies steps `0..squashed_steps` to an empty database and returns the resulting
+    /// schema, for use with `with_baseline`. Only the schema is kept, so any rows inserted by
+    /// the squashed steps must be inserted by a later step instead
+    pub fn squash(&self, squashed_steps: usize) -> Result<String> {
+        let steps = self.steps();
+        if squashed_steps > steps.len() {
+            return Err(anyhow!(
+                "Cannot squash {} steps of {}, which only has {}",
+                squashed_steps,
+                self.domain,
+                steps.len()
+            ));
+        }
+
+        let connection = Connection::open(":memory:", false)?;
+        for step in &steps[..squashed_steps] {
+            step.execute(&connection)?;
+        }
+
+        let schema = connection
+            .prepare(indoc! {"
+                SELECT sql FROM sqlite_master
+                WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_%'
+                ORDER BY rowid"})?
+            .rows::<String>()?;
+        Ok(schema
+            .into_iter()
+            .map(|sql| format!("{};", sql))
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    fn run_unchecked(&self, connection: &Connection) -> Result<()> {
+        connection.exec(self.migrations.join(";\n"))
+    }
+
+    /// Every step of the migration, with any baseline standing in for the squashed steps
+    fn steps(&self) -> Vec<Step> {
+        let steps = if self.steps.is_empty() {
+            self.migrations.iter().copied().map(Step::Sql).collect()
+        } else {
+            self.steps.to_vec()
+        };
+
+        match self.baseline {
+            Some((baseline, squashed_steps)) => (0..squashed_steps)
+                .map(|index| Step::Sql(if index == 0 { baseline } else { SQUASHED_STEP }))
+                .chain(steps)
+                .collect(),
+            None => steps,
+        }
+    }
+
+    /// Whether a step was squashed into the baseline. Squashed steps match whatever was
+    /// stored for them, since that is either the original step or the baseline
+    fn is_squashed(&self, index: usize) -> bool {
+        self.baseline
+            .is_some_and(|(_, squashed_steps)| index < squashed_steps)
+    }
+
+    /// Applies any steps which haven't been run yet. Unless the connection is already in a
+    /// transaction, the database is locked exclusively while the applied steps are read and
+    /// the rest are run, so other processes migrating the same file wait their turn
+    pub fn run(&self, connection: &Connection) -> Result<()> {
+        with_migration_lock(connection, &[self], false, |connection| {
+            self.run_locked(connection)
+        })
+    }
+
+    fn run_locked(&self, connection: &Connection) -> Result<()> {
+        setup_migrations_table(connection)?;
+
+        if let Some(changed_migration) = self.find_changed_migration(connection)? {
+            self.recover(connection, changed_migration)?;
+        }
+
+        let completed_migrations = self.completed_migrations(connection)?;
+        if let Some((_, squashed_steps)) = self.baseline {
+            if !completed_migrations.is_empty() && completed_migrations.len() < squashed_steps {
+                return Err(anyhow!(
+                    "{} has only completed {} of the {} steps squashed into its baseline",
+                    self.domain,
+                    completed_migrations.len(),
+                    squashed_steps
+                ));
+            }
+        }
+
+        let mut store_completed_migration = connection.prepare(
+            "INSERT INTO migrations (domain, step, migration, hash) VALUES (?, ?, ?, ?)",
+        )?;
+
+        for (index, step) in self.steps().into_iter().enumerate() {
+            let migration = step.text();
+            let hash = migration_hash(&migration);
+
+            if let Some((_, completed_migration, completed_hash)) = completed_migrations.get(index)
+            {
+                if *completed_hash == hash || self.is_squashed(index) {
+                    // Migration already run. Continue
+                    continue;
+                } else if self.amended_steps.contains(&index) {
+                    connection
+                        .prepare(indoc! {"
+                            UPDATE migrations SET migration = ?, hash = ?
+                            WHERE domain = ? AND step = ?"})?
+                        .bound((migration.as_ref(), hash.as_str(), self.domain, index))?
+                        .run()?;
+                    continue;
+                } else {
+                    return Err(anyhow!(ChangedMigration {
+                        domain: self.domain,
+                        step: index,
+                        stored: completed_migration.clone(),
+                        proposed: migration.into_owned(),
+                    }
+                    .to_string()));
+                }
+            }
+
+            if self.non_transactional_steps.contains(&index) {
+                step.execute(connection)?;
+                store_completed_migration
+                    .bound((self.domain, index, migration.as_ref(), hash.as_str()))?
+                    .run()?;
+            } else {
+                // Run the step and record it together so a failure part way through a step
+                // or before it is recorded leaves no trace
+                connection.with_savepoint("migration_step", |connection| {
+                    step.execute(connection)?;
+                    store_completed_migration
+                        .bound((self.domain, index, migration.as_ref(), hash.as_str()))?
+                        .run()?;
+                    Ok(Some(()))
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs a set of migrations, ordering them so that every migration runs after the domains
+    /// it depends on. Dependencies on domains outside of the set must already be satisfied by
+    /// the database. Errors if the dependencies contain a cycle
+    pub fn run_all(connection: &Connection, migrations: &[&Migration]) -> Result<()> {
+        with_migration_lock(connection, migrations, false, |connection| {
+            Self::run_all_locked(connection, migrations)
+        })
+    }
+
+    /// Runs a set of migrations while setting up a connection. When every step is
+    /// transactional, the lock transaction is rolled back if any of them fail, so a failure
+    /// leaves the database untouched. Otherwise each step is applied on its own under the
+    /// lock row
+    pub(crate) fn run_all_for_setup(
+        connection: &Connection,
+        migrations: &[&Migration],
+    ) -> Result<()> {
+        with_migration_lock(connection, migrations, true, |connection| {
+            Self::run_all_locked(connection, migrations)
+        })
+    }
+
+    fn run_all_locked(connection: &Connection, migrations: &[&Migration]) -> Result<()> {
+        setup_migrations_table(connection)?;
+
+        for migration in Self::dependency_order(migrations)? {
+            for dependency in migration.dependencies {
+                let completed_steps = connection
+                    .prepare("SELECT COUNT(*) FROM migrations WHERE domain = ?")?
+                    .bound(dependency.domain)?
+                    .row::<usize>()?;
+                let required_steps = dependency.step.map_or(1, |step| step + 1);
+
+                if completed_steps < required_steps {
+                    return Err(anyhow!(
+                        "{} depends on {}{}, which has only completed {} steps",
+                        migration.domain,
+                        dependency.domain,
+                        dependency
+                            .step
+                            .map(|step| format!(" at step {}", step))
+                            .unwrap_or_default(),
+                        completed_steps
+                    ));
+                }
+            }
+
+            migration.run_locked(connection)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sorts the migrations so dependencies come first, otherwise preserving the given order
+    fn dependency_order<'a>(migrations: &[&'a Migration]) -> Result<Vec<&'a Migration>> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Mark {
+            Unvisited,
+            Visiting,
+            Visited,
+        }
+
+        fn visit<'a>(
+            index: usize,
+            migrations: &[&'a Migration],
+            marks: &mut [Mark],
+            path: &mut Vec<&'static str>,
+            ordered: &mut Vec<&'a Migration>,
+        ) -> Result<()> {
+            let migration = migrations[index];
+            match marks[index] {
+                Mark::Visited => return Ok(()),
+                Mark::Visiting => {
+                    path.push(migration.domain);
+                    let cycle_start = path.iter().position(|domain| *domain == migration.domain);
+                    return Err(anyhow!(
+                        "Migration dependency cycle: {}",
+                        path[cycle_start.unwrap_or_default()..].join(" -> ")
+                    ));
+                }
+                Mark::Unvisited => {}
+            }
+
+            marks[index] = Mark::Visiting;
+            path.push(migration.domain);
+            for dependency in migration.dependencies {
+                if let Some(dependency_index) = migrations
+                    .iter()
+                    .position(|migration| migration.domain == dependency.domain)
+                {
+                    visit(dependency_index, migrations, marks, path, ordered)?;
+                }
+            }
+            path.pop();
+            marks[index] = Mark::Visited;
+            ordered.push(migration);
+            Ok(())
+        }
+
+        for (index, migration) in migrations.iter().enumerate() {
+            if migrations[..index]
+                .iter()
+                .any(|other| other.domain == migration.domain)
+            {
+                return Err(anyhow!(
+                    "Domain {} migrated more than once",
+                    migration.domain
+                ));
+            }
+        }
+
+        let mut marks = vec![Mark::Unvisited; migrations.len()];
+        let mut ordered = Vec::with_capacity(migrations.len());
+        for index in 0..migrations.len() {
+            visit(index, migrations, &mut marks, &mut Vec::new(), &mut ordered)?;
+        }
+        Ok(ordered)
+    }
+
+    /// Reports which steps are applied, pending or mismatched without modifying the database
+    pub fn status(&self, connection: &Connection) -> Result<MigrationStatus> {
+        let migrations_table_exists = connection
+            .prepare(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'migrations'",
+            )?
+            .row::<usize>()?
+            > 0;
+        // Hashes are recomputed from the stored text so that tables from before hashes were
+        // stored can be reported on without upgrading them
+        let stored_migrations = if migrations_table_exists {
+            connection
+                .prepare("SELECT step, migration FROM migrations WHERE domain = ? ORDER BY step")?
+                .bound(self.domain)?
+                .rows::<(usize, String)>()?
+        } else {
+            Vec::new()
+        };
+
+        let steps = self.steps();
+        let mut status = MigrationStatus {
+            domain: self.domain,
+            ..Default::default()
+        };
+
+        for (index, step) in steps.iter().enumerate() {
+            let proposed = step.text();
+            match stored_migrations
+                .iter()
+                .find(|(stored_step, _)| *stored_step == index)
+            {
+                None => status.pending.push(index),
+                Some((_, stored))
+                    if migration_hash(stored) == migration_hash(&proposed)
+                        || self.amended_steps.contains(&index)
+                        || self.is_squashed(index) =>
+                {
+                    status.applied.push(index)
+                }
+                Some((_, stored)) => status.mismatched.push(MismatchedStep {
+                    step: index,
+                    diff: line_diff(stored, &proposed),
+                    stored: stored.clone(),
+                    proposed: proposed.into_owned(),
+                }),
+            }
+        }
+
+        status.unknown = stored_migrations
+            .iter()
+            .map(|(step, _)| *step)
+            .filter(|step| *step >= steps.len())
+            .collect();
+
+        Ok(status)
+    }
+
+    /// Reports the status of each migration in a set
+    pub fn status_all(
+        connection: &Connection,
+        migrations: &[&Migration],
+    ) -> Result<Vec<MigrationStatus>> {
+        migrations
+            .iter()
+            .map(|migration| migration.status(connection))
+            .collect()
+    }
+
+    /// Runs the migration inside a savepoint which is always rolled back, reporting which steps
+    /// would be applied and any error. Mismatched steps are reported along with what the
+    /// changed policy would do about them, which is simulated without backing up the database
+    /// or calling callbacks. Non transactional steps can't be dry run and will report an error
+    pub fn dry_run(&self, connection: &Connection) -> Result<DryRun> {
+        Ok(Self::dry_run_all(connection, &[self])?.remove(0))
+    }
+
+    /// Dry runs a set of migrations in dependency order within a single savepoint, so later
+    /// domains see the changes earlier domains would have made. Stops at the first error
+    pub fn dry_run_all(connection: &Connection, migrations: &[&Migration]) -> Result<Vec<DryRun>> {
+        let mut dry_runs = Vec::new();
+
+        connection.with_savepoint("dry_run_migrations", |connection| {
+            for migration in Self::dependency_order(migrations)? {
+                let status = migration.status(connection)?;
+                let recovery =
+                    (!status.mismatched.is_empty()).then(|| migration.changed_policy.recovery());
+
+                // The Error policy has no side effects, so only the others need simulating
+                let result = match recovery {
+                    None | Some(Recovery::Fail) => Self::run_all(connection, &[migration]),
+                    Some(Recovery::Recreate | Recovery::Reapply) => migration
+                        .drop_for_recovery(connection)
+                        .and_then(|_| Self::run_all(connection, &[migration])),
+                    Some(Recovery::Callback) => Ok(()),
+                };
+                let kept = match recovery {
+                    Some(Recovery::Recreate | Recovery::Reapply) => Vec::new(),
+                    _ => status.applied,
+                };
+                let applied_after = migration.status(connection)?.applied;
+
+                dry_runs.push(DryRun {
+                    domain: migration.domain,
+                    applied: applied_after
+                        .into_iter()
+                        .filter(|step| !kept.contains(step))
+                        .collect(),
+                    mismatched: status.mismatched,
+                    recovery,
+                    error: result.err().map(|error| format!("{:#}", error)),
+                });
+
+                if dry_runs
+                    .last()
+                    .is_some_and(|dry_run| dry_run.error.is_some())
+                {
+                    break;
+                }
+            }
+
+            // Always roll back
+            Ok(None::<()>)
+        })?;
+
+        Ok(dry_runs)
+    }
+
+    /// Exercises every upgrade path for tests. For each k, a fresh database has steps `0..k`
+    /// applied, then `seed` is called with the database and k to insert data the way an old
+    /// version would have, the remaining steps are applied and `check` is called to assert on
+    /// the result. Squashed steps are only applied as a whole, so k skips over the baseline.
+    /// Errors are annotated with the k which produced them
+    pub fn test_upgrades(
+        &self,
+        mut seed: impl FnMut(&Connection, usize) -> Result<()>,
+        mut check: impl FnMut(&Connection, usize) -> Result<()>,
+    ) -> Result<()> {
+        let squashed_steps = self
+            .baseline
+            .map_or(0, |(_, squashed_steps)| squashed_steps);
+        let starting_steps = (0..=self.steps().len())
+            .filter(|applied_steps| *applied_steps == 0 || *applied_steps >= squashed_steps);
+
+        for applied_steps in starting_steps {
+            let connection = Connection::open(":memory:", false)?;
+            self.prefix(applied_steps)
+                .run(&connection)
+                .and_then(|_| seed(&connection, applied_steps))
+                .and_then(|_| self.run(&connection))
+                .and_then(|_| check(&connection, applied_steps))
+                .with_context(|| {
+                    format!(
+                        "Upgrading {} from step {} failed",
+                        self.domain, applied_steps
+                    )
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// A copy of this migration with only its first `applied_steps` steps. A prefix shorter
+    /// than the squashed steps leaves out the baseline entirely
+    fn prefix(&self, applied_steps: usize) -> Migration {
+        let (baseline, own_steps) = match self.baseline {
+            Some((_, squashed_steps)) if applied_steps < squashed_steps => (None, 0),
+            Some((baseline, squashed_steps)) => (
+                Some((baseline, squashed_steps)),
+                applied_steps - squashed_steps,
+            ),
+            None => (None, applied_steps),
+        };
+
+        Migration {
+            migrations: &self.migrations[..own_steps.min(self.migrations.len())],
+            steps: &self.steps[..own_steps.min(self.steps.len())],
+            baseline,
+            ..*self
+        }
+    }
+
+    /// Marks the database as belonging to the application, or errors if it belongs to another.
+    /// Unmarked databases are claimed if they are empty or were already migrated by sqlez,
+    /// since those were created before the application id was set
+    pub fn claim_application_id(connection: &Connection, application_id: i32) -> Result<()> {
+        let current_id = connection.application_id()?;
+        if current_id == application_id {
+            return Ok(());
+        }
+
+        if current_id == 0 {
+            let objects = all_object_names(connection)?;
+            if objects.is_empty() || objects.iter().any(|(_, name)| name == "migrations") {
+                return connection.set_application_id(application_id);
+            }
+        }
+
+        Err(anyhow!(
+            "Database belongs to another application: expected application id {:#010x}, found {:#010x}",
+            application_id,
+            current_id
+        ))
+    }
+
+    /// Sets `PRAGMA user_version` to the number of steps applied across every domain, so
+    /// tools without access to the migrations can tell when the schema changed
+    pub fn update_user_version(connection: &Connection) -> Result<()> {
+        setup_migrations_table(connection)?;
+        let applied_steps = connection
+            .prepare("SELECT COUNT(*) FROM migrations")?
+            .row::<i32>()?;
+        connection.set_user_version(applied_steps)
+    }
+
+    /// Undoes every completed step from `step` onwards in reverse order, leaving steps
+    /// `0..step` applied. All of the down migrations run within a savepoint, so if any step
+    /// is missing a down migration or fails, nothing is rolled back
+    pub fn rollback_to(&self, connection: &Connection, step: usize) -> Result<()> {
+        setup_migrations_table(connection)?;
+
+        connection.with_savepoint("rollback_migration", |connection| {
+            let steps = self.steps();
+            let completed_migrations = self.completed_migrations(connection)?;
+            let mut remove_completed_migration =
+                connection.prepare("DELETE FROM migrations WHERE domain = ? AND step = ?")?;
+
+            for (index, _, completed_hash) in completed_migrations.iter().rev() {
+                if *index < step {
+                    break;
+                }
+
+                if self.is_squashed(*index) {
+                    return Err(anyhow!(
+                        "Cannot roll back {} at step {}, it was squashed into the baseline",
+                        self.domain,
+                        index
+                    ));
+                }
+
+                let proposed_hash = steps.get(*index).map(|step| migration_hash(&step.text()));
+                if proposed_hash.as_ref() != Some(completed_hash) {
+                    return Err(anyhow!(
+                        "Cannot roll back {} at step {}, the stored migration doesn't match",
+                        self.domain,
+                        index
+                    ));
+                }
+
+                let down_migration = self
+                    .down_migrations
+                    .get(*index)
+                    .copied()
+                    .flatten()
+                    .ok_or_else(|| {
+                        anyhow!("No down migration for {} at step {}", self.domain, index)
+                    })?;
+
+                connection.exec(down_migration)?;
+                remove_completed_migration
+                    .bound((self.domain, *index))?
+                    .run()?;
+            }
+
+            Ok(Some(()))
+        })?;
+
+        Ok(())
+    }
+
+    /// Returns the first completed step which doesn't match and isn't marked as amended
+    fn find_changed_migration(&self, connection: &Connection) -> Result<Option<ChangedMigration>> {
+        let completed_migrations = self.completed_migrations(connection)?;
+        Ok(self
+            .steps()
+            .into_iter()
+            .map(|step| step.text())
+            .zip(completed_migrations)
+            .enumerate()
+            .find(|(index, (migration, (_, _, completed_hash)))| {
+                migration_hash(migration) != *completed_hash
+                    && !self.amended_steps.contains(index)
+                    && !self.is_squashed(*index)
+            })
+            .map(|(step, (migration, (_, stored, _)))| ChangedMigration {
+                domain: self.domain,
+                step,
+                stored,
+                proposed: migration.into_owned(),
+            }))
+    }
+
+    fn recover(&self, connection: &Connection, changed_migration: ChangedMigration) -> Result<()> {
+        // Every policy other than returning an error may be destructive
+        if !matches!(self.changed_policy, ChangedPolicy::Error) {
+            backup_before_recovery(connection).with_context(|| {
+                format!("Could not back up before recovering\n{}", changed_migration)
+            })?;
+        }
+
+        match self.changed_policy {
+            ChangedPolicy::Error => Err(anyhow!(changed_migration.to_string())),
+            ChangedPolicy::Callback(callback) => callback(connection, &changed_migration),
+            ChangedPolicy::BackupAndRecreate | ChangedPolicy::DropDomainAndReapply => {
+                self.drop_for_recovery(connection)
+            }
+        }
+    }
+
+    /// Drops the objects the BackupAndRecreate and DropDomainAndReapply policies remove before
+    /// the steps are applied again
+    fn drop_for_recovery(&self, connection: &Connection) -> Result<()> {
+        match self.changed_policy {
+            ChangedPolicy::Error | ChangedPolicy::Callback(_) => Ok(()),
+            ChangedPolicy::BackupAndRecreate => {
+                drop_objects(connection, &all_object_names(connection)?)?;
+                setup_migrations_table(connection)
+            }
+            ChangedPolicy::DropDomainAndReapply => {
+                let stored_migrations = self
+                    .completed_migrations(connection)?
+                    .into_iter()
+                    .map(|(_, migration, _)| migration)
+                    .collect::<Vec<_>>();
+                drop_objects(connection, &created_object_names(&stored_migrations))?;
+                connection
+                    .prepare("DELETE FROM migrations WHERE domain = ?")?
+                    .bound(self.domain)?
+                    .run()
+            }
+        }
+    }
+
+    fn completed_migrations(
+        &self,
+        connection: &Connection,
+    ) -> Result<Vec<(usize, String, String)>> {
+        connection
+            .prepare(indoc! {"
+                SELECT step, migration, hash FROM migrations
+                WHERE domain = ?
+                ORDER BY step
+                "})?
+            .bound(self.domain)?
+            .rows::<(usize, String, String)>()
+    }
+}
+
+/// Runs f while holding the database's write lock, waiting up to MIGRATION_LOCK_TIMEOUT for
+/// other connections to release it. Only one connection can hold the lock at a time, so
+/// everything f reads is current and no other connection can apply the same steps
+/// concurrently.
+/// Usually the lock is a `BEGIN IMMEDIATE` transaction. Readers aren't blocked, which keeps
+/// backing up before recovery possible. Unless `atomic` is set, the transaction is committed
+/// even if f fails since each step is already applied atomically in its own savepoint.
+/// Non transactional steps can't run inside that transaction, so migrations with any instead
+/// claim a row in the `migration_lock` table for the duration of the run. While the run lasts,
+/// a second connection keeps bumping the row's heartbeat. Waiting connections take the row
+/// over once its heartbeat has stood still for MIGRATION_LOCK_STALE, not counting time spent
+/// waiting on the write lock, which the row's owner may be holding. In memory databases can't
+/// be opened a second time to send heartbeats, so their rows only stay live for that long.
+/// If the connection is already in a transaction, it is up to the caller to have locked the
+/// database
+fn with_migration_lock<R>(
+    connection: &Connection,
+    migrations: &[&Migration],
+    atomic: bool,
+    f: impl FnOnce(&Connection) -> Result<R>,
+) -> Result<R> {
+    if connection.in_transaction() {
+        return f(connection);
+    }
+
+    let started = Instant::now();
+    let mut last_claim = None;
+    let mut unchanged_for = Duration::ZERO;
+    loop {
+        connection
+            .begin_retrying(
+                "BEGIN IMMEDIATE",
+                MIGRATION_LOCK_TIMEOUT.saturating_sub(started.elapsed()),
+            )
+            .context("Could not lock the database to migrate")?;
+
+        let claim = match migration_lock_claim(connection) {
+            Ok(claim) => claim,
+            Err(error) => {
+                connection.exec("ROLLBACK").ok();
+                return Err(error);
+            }
+        };
+        let Some(claim) = claim else {
+            break;
+        };
+
+        if last_claim.as_ref() == Some(&claim) {
+            unchanged_for += MIGRATION_LOCK_POLL;
+        } else {
+            last_claim = Some(claim);
+            unchanged_for = Duration::ZERO;
+        }
+        if unchanged_for >= MIGRATION_LOCK_STALE {
+            if let Err(error) = connection.exec("DELETE FROM migration_lock") {
+                connection.exec("ROLLBACK").ok();
+                return Err(error);
+            }
+            break;
+        }
+
+        connection.exec("ROLLBACK")?;
+        if started.elapsed() >= MIGRATION_LOCK_TIMEOUT {
+            return Err(anyhow!(
+                "Timed out waiting for another connection to finish migrating"
+            ));
+        }
+        thread::sleep(MIGRATION_LOCK_POLL);
+    }
+
+    if migrations
+        .iter()
+        .all(|migration| migration.non_transactional_steps.is_empty())
+    {
+        let result = f(connection);
+        if result.is_err() && atomic {
+            connection.exec("ROLLBACK")?;
+            return result;
+        }
+        if let Err(error) = connection.exec("COMMIT") {
+            connection.exec("ROLLBACK").ok();
+            return result.and(Err(error));
+        }
+        return result;
+    }
+
+    let owner = format!(
+        "{}-{}-{}",
+        process::id(),
+        unix_time()?,
+        NEXT_LOCK_OWNER.fetch_add(1, Ordering::Relaxed)
+    );
+    let claimed = connection
+        .exec("CREATE TABLE IF NOT EXISTS migration_lock (owner TEXT, heartbeat INTEGER)")
+        .and_then(|_| connection.exec("DELETE FROM migration_lock"))
+        .and_then(|_| {
+            connection
+                .prepare("INSERT INTO migration_lock (owner, heartbeat) VALUES (?, 0)")?
+                .bound(owner.as_str())?
+                .run()
+        })
+        .and_then(|_| connection.exec("COMMIT"));
+    if let Err(error) = claimed {
+        connection.exec("ROLLBACK").ok();
+        return Err(error.context("Could not lock the database to migrate"));
+    }
+
+    // Other connections briefly lock the database to check the claim, so writes outside of
+    // the lock transaction wait for them rather than failing
+    let busy_timeout = connection.busy_timeout()?;
+    connection.set_busy_timeout(MIGRATION_LOCK_TIMEOUT)?;
+    let heartbeat = LockHeartbeat::spawn(connection, &owner);
+    let result = heartbeat.and_then(|heartbeat| {
+        let result = f(connection);
+        heartbeat.stop();
+        result
+    });
+    let released = connection
+        .prepare("DELETE FROM migration_lock WHERE owner = ?")
+        .and_then(|mut statement| statement.bound(owner.as_str())?.run());
+    connection.set_busy_timeout(busy_timeout)?;
+    result.and_then(|result| released.map(|_| result))
+}
+
+/// The owner and heartbeat of the claimed migration lock row, if there is one
+fn migration_lock_claim(connection: &Connection) -> Result<Option<(String, i64)>> {
+    let table_exists = connection
+        .prepare(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'migration_lock'",
+        )?
+        .row::<usize>()?
+        > 0;
+    if !table_exists {
+        return Ok(None);
+    }
+
+    connection
+        .prepare("SELECT owner, heartbeat FROM migration_lock LIMIT 1")?
+        .maybe_row::<(String, i64)>()
+}
+
+/// Bumps the heartbeat of a migration lock row from a thread with its own connection, so
+/// that steps which run for longer than MIGRATION_LOCK_STALE keep their claim
+struct LockHeartbeat {
+    stop: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+impl LockHeartbeat {
+    fn spawn(connection: &Connection, owner: &str) -> Result<Self> {
+        let Some(path) = connection.main_file_path() else {
+            return Ok(Self { stop: None });
+        };
+
+        let heartbeat = Connection::open(&path, true)?;
+        heartbeat.set_busy_timeout(MIGRATION_LOCK_TIMEOUT)?;
+        let owner = owner.to_string();
+        let (sender, receiver) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("sqlez migration lock heartbeat".to_string())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) =
+                    receiver.recv_timeout(MIGRATION_LOCK_HEARTBEAT)
+                {
+                    heartbeat
+                        .prepare(
+                            "UPDATE migration_lock SET heartbeat = heartbeat + 1 WHERE owner = ?",
+                        )
+                        .and_then(|mut statement| statement.bound(owner.as_str())?.run())
+                        .ok();
+                }
+            })?;
+
+        Ok(Self {
+            stop: Some((sender, thread)),
+        })
+    }
+
+    fn stop(self) {
+        if let Some((sender, thread)) = self.stop {
+            drop(sender);
+            thread.join().ok();
+        }
+    }
+}
+
+fn unix_time() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Creates the migrations table if needed. Tables created before hashes were stored have the
+/// hash column added, and any rows without a hash are backfilled from their stored text
+fn setup_migrations_table(connection: &Connection) -> Result<()> {
+    MIGRATIONS_MIGRATION.run_unchecked(connection)?;
+
+    let has_hash_column = connection
+        .prepare("SELECT COUNT(*) FROM pragma_table_info('migrations') WHERE name = 'hash'")?
+        .row::<usize>()?
+        > 0;
+    if !has_hash_column {
+        connection.exec("ALTER TABLE migrations ADD COLUMN hash TEXT")?;
+    }
+
+    let unhashed_migrations = connection
+        .prepare("SELECT rowid, migration FROM migrations WHERE hash IS NULL")?
+        .rows::<(i64, String)>()?;
+    let mut store_hash = connection.prepare("UPDATE migrations SET hash = ? WHERE rowid = ?")?;
+    for (row_id, migration) in unhashed_migrations {
+        store_hash
+            .bound((migration_hash(&migration), row_id))?
+            .run()?;
+    }
+
+    Ok(())
+}
+
+/// Backs up a file backed database next to the original as `<path>.<unix time>.bak`. In memory
+/// databases don't outlive the process, so they aren't backed up.
+/// Sqlite can't back up from a connection with an open write transaction, so in that case the
+/// last committed state is backed up through a second connection instead
+fn backup_before_recovery(connection: &Connection) -> Result<()> {
+    let Some(path) = connection.main_file_path() else {
+        return Ok(());
+    };
+
+    let backup = Connection::open(&format!("{}.{}.bak", path, unix_time()?), true)?;
+    if connection.in_transaction() {
+        Connection::open(&path, true)?.backup_main(&backup)
+    } else {
+        connection.backup_main(&backup)
+    }
+}
+
+/// Lists every table, view and trigger in the database other than sqlite's own and the
+/// migration lock, which may be held by the run listing them
+fn all_object_names(connection: &Connection) -> Result<Vec<(String, String)>> {
+    connection
+        .prepare(indoc! {"
+            SELECT type, name FROM sqlite_master
+            WHERE type IN ('table', 'view', 'trigger') AND name NOT LIKE 'sqlite_%'
+                AND name != 'migration_lock'
+            ORDER BY rowid"})?
+        .rows::<(String, String)>()
+}
+
+/// Scans migrations for the tables, views, triggers and indexes they create
+fn created_object_names(migrations: &[String]) -> Vec<(String, String)> {
+    let mut created = Vec::new();
+    for migration in migrations {
+        let normalized = normalize_migration(migration).to_lowercase();
+        let words = normalized
+            .split(|char: char| char.is_whitespace() || matches!(char, '(' | ')' | ',' | ';'))
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>();
+
+        for (index, word) in words.iter().enumerate() {
+            if *word != "create" {
+                continue;
+            }
+
+            let mut rest = words[index + 1..]
+                .iter()
+                .skip_while(|word| matches!(**word, "temp" | "temporary" | "unique" | "virtual"));
+            let Some(object_type) = rest.next() else {
+                continue;
+            };
+            if !matches!(*object_type, "table" | "view" | "trigger" | "index") {
+                continue;
+            }
+
+            let mut rest = rest.skip_while(|word| matches!(**word, "if" | "not" | "exists"));
+            if let Some(name) = rest.next() {
+                let name = name.trim_matches(|char| matches!(char, '"' | '`' | '[' | ']'));
+                created.push((object_type.to_string(), name.to_string()));
+            }
+        }
+    }
+    created
+}
+
+/// Drops the given objects in reverse order so that objects are dropped before the ones they
+/// were created after. Foreign keys are deferred so tables referencing each other can be
+/// dropped, and objects which no longer exist are skipped
+fn drop_objects(connection: &Connection, objects: &[(String, String)]) -> Result<()> {
+    connection.exec("PRAGMA defer_foreign_keys = ON")?;
+    for (object_type, name) in objects.iter().rev() {
+        let exists = connection
+            .prepare(indoc! {"
+                SELECT COUNT(*) FROM sqlite_master
+                WHERE type = CAST(? AS TEXT) AND name = CAST(? AS TEXT) COLLATE NOCASE"})?
+            .bound((object_type.as_str(), name.as_str()))?
+            .row::<usize>()?
+            > 0;
+        if exists {
+            connection.exec(format!(
+                "DROP {} \"{}\"",
+                object_type.to_uppercase(),
+                name.replace('"', "\"\"")
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+/// Produces a line diff between two texts using the longest common subsequence of lines
+fn line_diff(old: &str, new: &str) -> String {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+
+    // lengths[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut lengths = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            diff.push(format!("  {}", old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lengths[i + 1][j] >= lengths[i][j + 1]) {
+            diff.push(format!("- {}", old[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+ {}", new[j]));
+            j += 1;
+        }
+    }
+    diff.join("\n")
+}
+
+/// Hashes the normalized form of a migration with 64 bit FNV-1a. The hash is stored in the
+/// database, so it must stay stable across releases and platforms
+fn migration_hash(migration: &str) -> String {
+    let hash = normalize_migration(migration)
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+    format!("{:016x}", hash)
+}
+
+/// Strips comments and collapses whitespace outside of quoted strings and identifiers.
+/// Whitespace next to punctuation and trailing semicolons are dropped entirely so that
+/// reflowing a query across lines doesn't change its normalized form
+pub(crate) fn normalize_migration(migration: &str) -> String {
+    fn is_punctuation(char: char) -> bool {
+        matches!(char, '(' | ')' | ',' | ';')
+    }
+
+    let mut normalized = String::with_capacity(migration.len());
+    let mut pending_space = false;
+    let mut chars = migration.chars().peekable();
+
+    while let Some(char) = chars.next() {
+        match char {
+            '-' if chars.peek() == Some(&'-') => {
+                for char in chars.by_ref() {
+                    if char == '\n' {
+                        break;
+                    }
+                }
+                pending_space = true;
+                continue;
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = None;
+                for char in chars.by_ref() {
+                    if previous == Some('*') && char == '/' {
+                        break;
+                    }
+                    previous = Some(char);
+                }
+                pending_space = true;
+                continue;
+            }
+            char if char.is_whitespace() => {
+                pending_space = true;
+                continue;
+            }
+            _ => {}
+        }
+
+        if pending_space
+            && !is_punctuation(char)
+            && !normalized.ends_with(is_punctuation)
+            && !normalized.is_empty()
+        {
+            normalized.push(' ');
+        }
+        pending_space = false;
+        normalized.push(char);
+
+        // Copy quoted strings and identifiers verbatim. Doubled quotes used as escapes are
+        // handled naturally as the end of one quoted section followed by the start of another
+        if matches!(char, '\'' | '"' | '`' | '[') {
+            let closing = if char == '[' { ']' } else { char };
+            for char in chars.by_ref() {
+                normalized.push(char);
+                if char == closing {
+                    break;
+                }
+            }
+        }
+    }
+
+    normalized.trim_end_matches(';').to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use indoc::indoc;
+
+    use std::{fs, thread, time::Duration};
+
+    use crate::{
+        connection::Connection,
+        migrations::{
+            embed_migrations, normalize_migration, ChangedMigration, ChangedPolicy, Dependency,
+            Migration, Recovery, Step,
+        },
+        test_support::TempDir,
+    };
+
+    #[test]
+    fn test_migrations_are_added_to_table() {
+        let connection = Connection::open_memory("migrations_are_added_to_table");
+
+        // Create first migration with a single step and run it
+        let mut migration = Migration::new(
+            "test",
+            &[indoc! {"
+            CREATE TABLE test1 (
+                a TEXT,
+                b TEXT
+            );"}],
+        );
+        migration.run(&connection).unwrap();
+
+        // Verify it got added to the migrations table
+        assert_eq!(
+            &connection
+                .prepare("SELECT (migration) FROM migrations")
+                .unwrap()
+                .rows::<String>()
+                .unwrap()[..],
+            migration.migrations
+        );
+
+        // Add another step to the migration and run it again
+        migration.migrations = &[
+            indoc! {"
+                CREATE TABLE test1 (
+                    a TEXT,
+                    b TEXT
+                );"},
+            indoc! {"
+                CREATE TABLE test2 (
+                    c TEXT,
+                    d TEXT
+                );"},
+        ];
+        migration.run(&connection).unwrap();
+
+        // Verify it is also added to the migrations table
+        assert_eq!(
+            &connection
+                .prepare("SELECT (migration) FROM migrations")
+                .unwrap()
+                .rows::<String>()
+                .unwrap()[..],
+            migration.migrations
+        );
+    }
+
+    #[test]
+    fn test_migration_setup_works() {
+        let connection = Connection::open_memory("migration_setup_works");
+
+        connection
+            .exec(indoc! {"CREATE TABLE IF NOT EXISTS migrations (
+                    domain TEXT,
+                    step INTEGER,
+                    migration TEXT
+                );"})
+            .unwrap();
+
+        let mut store_completed_migration = connection
+            .prepare(indoc! {"
+                INSERT INTO migrations (domain, step, migration)
+                VALUES (?, ?, ?)"})
+            .unwrap();
+
+        let domain = "test_domain";
+        for i in 0..5 {
+            // Create a table forcing a schema change
+            connection
+                .exec(format!("CREATE TABLE table{} ( test TEXT );", i))
+                .unwrap();
+
+            store_completed_migration
+                .bound((domain, i, i.to_string()))
+                .unwrap()
+                .run()
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn migrations_dont_rerun() {
+        let connection = Connection::open_memory("migrations_dont_rerun");
+
+        // Create migration which clears a table
+        let migration = Migration::new("test", &["DELETE FROM test_table"]);
+
+        // Manually create the table for that migration with a row
+        connection
+            .exec(indoc! {"
+            CREATE TABLE test_table (
+                test_column INTEGER
+            );
+            INSERT INTO test_table (test_column) VALUES (1)"})
+            .unwrap();
+
+        assert_eq!(
+            connection
+                .prepare("SELECT * FROM test_table")
+                .unwrap()
+                .row::<usize>()
+                .unwrap(),
+            1
+        );
+
+        // Run the migration verifying that the row got dropped
+        migration.run(&connection).unwrap();
+        assert_eq!(
+            connection
+                .prepare("SELECT * FROM test_table")
+                .unwrap()
+                .rows::<usize>()
+                .unwrap(),
+            Vec::new()
+        );
+
+        // Recreate the dropped row
+        connection
+            .exec("INSERT INTO test_table (test_column) VALUES (2)")
+            .unwrap();
+
+        // Run the same migration again and verify that the table was left unchanged
+        migration.run(&connection).unwrap();
+        assert_eq!(
+            connection
+                .prepare("SELECT * FROM test_table")
+                .unwrap()
+                .row::<usize>()
+                .unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn changed_migration_fails() {
+        let connection = Connection::open_memory("changed_migration_fails");
+
+        // Create a migration with two steps and run it
+        Migration::new(
+            "test migration",
+            &[
+                indoc! {"
+                CREATE TABLE test (
+                    col INTEGER
+                )"},
+                indoc! {"
+                INSERT INTO test (col) VALUES (1)"},
+            ],
+        )
+        .run(&connection)
+        .unwrap();
+
+        // Create another migration with the same domain but different steps
+        let second_migration_result = Migration::new(
+            "test migration",
+            &[
+                indoc! {"
+                CREATE TABLE test (
+                    color INTEGER
+                )"},
+                indoc! {"
+                INSERT INTO test (color) VALUES (1)"},
+            ],
+        )
+        .run(&connection);
+
+        // Verify new migration returns error when run
+        assert!(second_migration_result.is_err())
+    }
+
+    #[test]
+    fn rollback_runs_down_migrations_in_reverse() {
+        let connection = Connection::open_memory("rollback_runs_down_migrations_in_reverse");
+
+        let migration = Migration::new(
+            "test",
+            &[
+                "CREATE TABLE test1 (a TEXT)",
+                "CREATE TABLE test2 (b TEXT)",
+                "INSERT INTO test1 (a) VALUES ('row')",
+            ],
+        )
+        .with_down_migrations(&[
+            Some("DROP TABLE test1"),
+            Some("DROP TABLE test2"),
+            Some("DELETE FROM test1"),
+        ]);
+        migration.run(&connection).unwrap();
+
+        migration.rollback_to(&connection, 1).unwrap();
+
+        assert_eq!(
+            connection
+                .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+                .unwrap()
+                .rows::<String>()
+                .unwrap(),
+            vec!["migrations", "test1"]
+        );
+        assert_eq!(
+            connection
+                .prepare("SELECT COUNT(*) FROM test1")
+                .unwrap()
+                .row::<usize>()
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            connection
+                .prepare("SELECT step FROM migrations")
+                .unwrap()
+                .rows::<usize>()
+                .unwrap(),
+            vec![0]
+        );
+
+        // Running again reapplies the rolled back steps
+        migration.run(&connection).unwrap();
+        assert_eq!(
+            connection
+                .prepare("SELECT COUNT(*) FROM test1")
+                .unwrap()
+                .row::<usize>()
+                .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn rollback_without_down_migration_fails() {
+        let connection = Connection::open_memory("rollback_without_down_migration_fails");
+
+        let migration = Migration::new(
+            "test",
+            &["CREATE TABLE test1 (a TEXT)", "CREATE TABLE test2 (b TEXT)"],
+        )
+        .with_down_migrations(&[None, Some("DROP TABLE test2")]);
+        migration.run(&connection).unwrap();
+
+        assert!(migration.rollback_to(&connection, 0).is_err());
+
+        // Step 1 was rolled back before step 0 failed, so verify it was restored
+        assert_eq!(
+            connection
+                .prepare("SELECT COUNT(*) FROM migrations")
+                .unwrap()
+                .row::<usize>()
+                .unwrap(),
+            2
+        );
+        connection.exec("SELECT * FROM test2").unwrap();
+    }
+
+    #[test]
+    fn failed_step_is_not_partially_applied() {
+        let connection = Connection::open_memory("failed_step_is_not_partially_applied");
+
+        let result = Migration::new(
+            "test",
+            &[indoc! {"
+                CREATE TABLE test (col INTEGER);
+                INSERT INTO missing (col) VALUES (1);"}],
+        )
+        .run(&connection);
+        assert!(result.is_err());
+
+        assert_eq!(
+            connection
+                .prepare("SELECT COUNT(*) FROM sqlite_master WHERE name = 'test'")
+                .unwrap()
+                .row::<usize>()
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            connection
+                .prepare("SELECT COUNT(*) FROM migrations")
+                .unwrap()
+                .row::<usize>()
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn non_transactional_steps_run_outside_savepoint() {
+        let connection = Connection::open_memory("non_transactional_steps");
+
+        let migration = Migration::new("test", &["CREATE TABLE test (col INTEGER)", "VACUUM"]);
+        assert!(migration.run(&connection).is_err());
+
+        migration
+            .with_non_transactional_steps(&[1])
+            .run(&connection)
+            .unwrap();
+        assert_eq!(
+            connection
+                .prepare("SELECT step FROM migrations")
+                .unwrap()
+                .rows::<usize>()
+                .unwrap(),
+            vec![0, 1]
+        );
+    }
+
+    #[test]
+    fn abandoned_migration_lock_claims_are_taken_over() {
+        let connection = Connection::open_memory("abandoned_migration_lock_claims");
+        connection
+            .exec(indoc! {"
+                CREATE TABLE migration_lock (owner TEXT, heartbeat INTEGER);
+                INSERT INTO migration_lock (owner, heartbeat) VALUES ('exited', 0);"})
+            .unwrap();
+
+        Migration::new("test", &["CREATE TABLE test (col INTEGER)", "VACUUM"])
+            .with_non_transactional_steps(&[1])
+            .run(&connection)
+            .unwrap();
+        assert_eq!(
+            connection
+                .prepare("SELECT COUNT(*) FROM migration_lock")
+                .unwrap()
+                .row::<usize>()
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn normalization_ignores_formatting_and_comments() {
+        assert_eq!(
+            normalize_migration(indoc! {"
+                -- The test table
+                CREATE TABLE test (
+                    a TEXT, /* first */
+                    b TEXT DEFAULT 'two  spaces -- not a comment'
+                );"}),
+            "CREATE TABLE test(a TEXT,b TEXT DEFAULT 'two  spaces -- not a comment')"
+        );
+    }
+
+    #[test]
+    fn reformatted_migration_does_not_fail() {
+        let connection = Connection::open_memory("reformatted_migration_does_not_fail");
+
+        Migration::new(
+            "test",
+            &[indoc! {"
+                CREATE TABLE test (
+                    col INTEGER
+                )"}],
+        )
+        .run(&connection)
+        .unwrap();
+
+        Migration::new(
+            "test",
+            &["-- Reformatted\nCREATE TABLE test (col INTEGER);"],
+        )
+        .run(&connection)
+        .unwrap();
+    }
+
+    #[test]
+    fn amended_steps_update_stored_migration() {
+        let connection = Connection::open_memory("amended_steps_update_stored_migration");
+
+        Migration::new("test", &["CREATE TABLE test (col INTEGER)"])
+            .run(&connection)
+            .unwrap();
+
+        const AMENDED: &[&str] = &["CREATE TABLE IF NOT EXISTS test (col INTEGER)"];
+        assert!(Migration::new("test", AMENDED).run(&connection).is_err());
+
+        Migration::new("test", AMENDED)
+            .with_amended_steps(&[0])
+            .run(&connection)
+            .unwrap();
+        Migration::new("test", AMENDED).run(&connection).unwrap();
+
+        assert_eq!(
+            connection
+                .prepare("SELECT migration FROM migrations")
+                .unwrap()
+                .rows::<String>()
+                .unwrap(),
+            AMENDED
+        );
+    }
+
+    #[test]
+    fn migrations_table_without_hashes_is_upgraded() {
+        let connection = Connection::open_memory("migrations_table_without_hashes");
+
+        connection
+            .exec(indoc! {"
+                CREATE TABLE migrations (
+                    domain TEXT,
+                    step INTEGER,
+                    migration TEXT
+                );
+                CREATE TABLE test (col INTEGER);
+                INSERT INTO migrations (domain, step, migration)
+                VALUES (
+                    CAST('test' AS BLOB),
+                    0,
+                    CAST('CREATE TABLE test (col INTEGER)' AS BLOB)
+                );"})
+            .unwrap();
+
+        Migration::new(
+            "test",
+            &[
+                "CREATE TABLE test ( col INTEGER )",
+                "INSERT INTO test (col) VALUES (1)",
+            ],
+        )
+        .run(&connection)
+        .unwrap();
+
+        assert_eq!(
+            connection
+                .prepare("SELECT COUNT(*) FROM migrations WHERE hash IS NOT NULL")
+                .unwrap()
+                .row::<usize>()
+                .unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn drop_domain_and_reapply_leaves_other_domains() {
+        let connection = Connection::open_memory("drop_domain_and_reapply");
+
+        Migration::new("other", &["CREATE TABLE other (a TEXT)"])
+            .run(&connection)
+            .unwrap();
+        Migration::new(
+            "test",
+            &[
+                "CREATE TABLE test (a TEXT)",
+                "CREATE INDEX IF NOT EXISTS test_index ON test (a)",
+            ],
+        )
+        .run(&connection)
+        .unwrap();
+
+        Migration::new(
+            "test",
+            &[
+                "CREATE TABLE test (b TEXT)",
+                "CREATE INDEX IF NOT EXISTS test_index ON test (b)",
+            ],
+        )
+        .with_changed_policy(ChangedPolicy::DropDomainAndReapply)
+        .run(&connection)
+        .unwrap();
+
+        connection.exec("SELECT b FROM test").unwrap();
+        connection.exec("SELECT a FROM other").unwrap();
+        assert_eq!(
+            connection
+                .prepare("SELECT domain FROM migrations ORDER BY domain, step")
+                .unwrap()
+                .rows::<String>()
+                .unwrap(),
+            vec!["other", "test", "test"]
+        );
+    }
+
+    #[test]
+    fn backup_and_recreate_backs_up_file() {
+        let directory = TempDir::new("backup_and_recreate");
+        let path = directory.join("db.sqlite");
+
+        {
+            let connection = Connection::open_file(path.to_str().unwrap());
+            Migration::new("other", &["CREATE TABLE other (a TEXT)"])
+                .run(&connection)
+                .unwrap();
+            Migration::new("test", &["CREATE TABLE test (a TEXT)"])
+                .run(&connection)
+                .unwrap();
+
+            Migration::new("test", &["CREATE TABLE test (b TEXT)"])
+                .with_changed_policy(ChangedPolicy::BackupAndRecreate)
+                .run(&connection)
+                .unwrap();
+
+            connection.exec("SELECT b FROM test").unwrap();
+            assert!(connection.exec("SELECT a FROM other").is_err());
+        }
+
+        let backup_path = fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|extension| extension == "bak"))
+            .expect("Backup file was not created");
+        let backup = Connection::open_file(backup_path.to_str().unwrap());
+        backup.exec("SELECT a FROM test").unwrap();
+        backup.exec("SELECT a FROM other").unwrap();
+        drop(backup);
+    }
+
+    #[test]
+    fn callback_policy_can_resolve_change() {
+        fn drop_test_table(
+            connection: &Connection,
+            changed: &ChangedMigration,
+        ) -> anyhow::Result<()> {
+            assert_eq!(changed.step, 0);
+            connection.exec("DROP TABLE test")?;
+            connection
+                .prepare("DELETE FROM migrations WHERE domain = ?")?
+                .bound(changed.domain)?
+                .run()
+        }
+
+        let connection = Connection::open_memory("callback_policy_can_resolve_change");
+        Migration::new("test", &["CREATE TABLE test (a TEXT)"])
+            .run(&connection)
+            .unwrap();
 
-impl Migration {
-    pub const fn new(domain: &'static str, migrations: &'static [&'static str]) -> Self {
-        Self { domain, migrations }
+        Migration::new("test", &["CREATE TABLE test (b TEXT)"])
+            .with_changed_policy(ChangedPolicy::Callback(drop_test_table))
+            .run(&connection)
+            .unwrap();
+        connection.exec("SELECT b FROM test").unwrap();
     }
 
-    fn run_unchecked(&self, connection: &Connection) -> Result<()> {
-        connection.exec(self.migrations.join(";\n"))
-    }
+    #[test]
+    fn function_steps_run_in_order_with_sql_steps() {
+        fn uppercase_names(connection: &Connection) -> anyhow::Result<()> {
+            let names = connection
+                .prepare("SELECT rowid, name FROM test")?
+                .rows::<(i64, String)>()?;
+            let mut update = connection.prepare("UPDATE test SET name = ? WHERE rowid = ?")?;
+            for (row_id, name) in names {
+                update.bound((name.to_uppercase(), row_id))?.run()?;
+            }
+            Ok(())
+        }
 
-    pub fn run(&self, connection: &Connection) -> Result<()> {
-        // Setup the migrations table unconditionally
-        MIGRATIONS_MIGRATION.run_unchecked(connection)?;
+        const STEPS: &[Step] = &[
+            Step::Sql("CREATE TABLE test (name TEXT)"),
+            Step::Sql("INSERT INTO test (name) VALUES ('first')"),
+            Step::Function {
+                name: "uppercase_names",
+                version: 1,
+                function: uppercase_names,
+            },
+            Step::Sql("INSERT INTO test (name) VALUES ('second')"),
+        ];
 
-        let completed_migrations = connection
-            .prepare(indoc! {"
-                SELECT domain, step, migration FROM migrations
-                WHERE domain = ?
-                ORDER BY step
-                "})?
-            .bound(self.domain)?
-            .rows::<(String, usize, String)>()?;
+        let connection = Connection::open_memory("function_steps_run_in_order");
+        Migration::with_steps("test", STEPS)
+            .run(&connection)
+            .unwrap();
+        Migration::with_steps("test", STEPS)
+            .run(&connection)
+            .unwrap();
 
-        let mut store_completed_migration = connection
-            .prepare("INSERT INTO migrations (domain, step, migration) VALUES (?, ?, ?)")?;
-
-        for (index, migration) in self.migrations.iter().enumerate() {
-            if let Some((_, _, completed_migration)) = completed_migrations.get(index) {
-                if completed_migration != migration {
-                    return Err(anyhow!(formatdoc! {"
-                        Migration changed for {} at step {}
-                        
-                        Stored migration:
-                        {}
-                        
-                        Proposed migration:
-                        {}", self.domain, index, completed_migration, migration}));
-                } else {
-                    // Migration already run. Continue
-                    continue;
-                }
-            }
+        assert_eq!(
+            connection
+                .prepare("SELECT name FROM test ORDER BY rowid")
+                .unwrap()
+                .rows::<String>()
+                .unwrap(),
+            vec!["FIRST", "second"]
+        );
+        assert_eq!(
+            connection
+                .prepare("SELECT migration FROM migrations WHERE step = 2")
+                .unwrap()
+                .row::<String>()
+                .unwrap(),
+            "rust:uppercase_names@1"
+        );
 
-            connection.exec(migration)?;
-            store_completed_migration
-                .bound((self.domain, index, *migration))?
-                .run()?;
-        }
+        // Bumping the version of a completed function step counts as a change
+        const BUMPED: &[Step] = &[
+            Step::Sql("CREATE TABLE test (name TEXT)"),
+            Step::Sql("INSERT INTO test (name) VALUES ('first')"),
+            Step::Function {
+                name: "uppercase_names",
+                version: 2,
+                function: uppercase_names,
+            },
+        ];
+        assert!(Migration::with_steps("test", BUMPED)
+            .run(&connection)
+            .is_err());
+    }
 
-        Ok(())
+    #[test]
+    fn run_all_orders_by_dependencies() {
+        let connection = Connection::open_memory("run_all_orders_by_dependencies");
+
+        const EDITOR: Migration = Migration::new(
+            "editor",
+            &["CREATE TABLE editors (workspace_id INTEGER REFERENCES workspaces(id))"],
+        )
+        .with_dependencies(&[Dependency::at_step("workspace", 0)]);
+        const WORKSPACE: Migration =
+            Migration::new("workspace", &["CREATE TABLE workspaces (id INTEGER)"]);
+        const SETTINGS: Migration =
+            Migration::new("settings", &["CREATE TABLE settings (key TEXT)"]);
+
+        Migration::run_all(&connection, &[&EDITOR, &SETTINGS, &WORKSPACE]).unwrap();
+
+        assert_eq!(
+            connection
+                .prepare("SELECT domain FROM migrations ORDER BY rowid")
+                .unwrap()
+                .rows::<String>()
+                .unwrap(),
+            vec!["workspace", "editor", "settings"]
+        );
     }
-}
 
-#[cfg(test)]
-mod test {
-    use indoc::indoc;
+    #[test]
+    fn run_all_detects_cycles_and_missing_steps() {
+        let connection = Connection::open_memory("run_all_detects_cycles");
 
-    use crate::{connection::Connection, migrations::Migration};
+        const FIRST: Migration = Migration::new("first", &["CREATE TABLE first (a TEXT)"])
+            .with_dependencies(&[Dependency::on("second")]);
+        const SECOND: Migration = Migration::new("second", &["CREATE TABLE second (a TEXT)"])
+            .with_dependencies(&[Dependency::on("first")]);
+        let error = Migration::run_all(&connection, &[&FIRST, &SECOND]).unwrap_err();
+        assert!(error.to_string().contains("first -> second -> first"));
+
+        const NEEDS_LATER_STEP: Migration =
+            Migration::new("third", &["CREATE TABLE third (a TEXT)"])
+                .with_dependencies(&[Dependency::at_step("fourth", 1)]);
+        const FOURTH: Migration = Migration::new("fourth", &["CREATE TABLE fourth (a TEXT)"]);
+        assert!(Migration::run_all(&connection, &[&NEEDS_LATER_STEP, &FOURTH]).is_err());
+    }
 
     #[test]
-    fn test_migrations_are_added_to_table() {
-        let connection = Connection::open_memory("migrations_are_added_to_table");
+    fn status_reports_applied_pending_and_mismatched_steps() {
+        let connection = Connection::open_memory("status_reports_steps");
 
-        // Create first migration with a single step and run it
-        let mut migration = Migration::new(
+        const ORIGINAL: Migration = Migration::new(
             "test",
-            &[indoc! {"
-            CREATE TABLE test1 (
-                a TEXT,
-                b TEXT
-            );"}],
+            &[
+                "CREATE TABLE test (a TEXT)",
+                "CREATE TABLE other (\n    a TEXT\n)",
+            ],
         );
-        migration.run(&connection).unwrap();
+        assert_eq!(ORIGINAL.status(&connection).unwrap().pending, vec![0, 1]);
+        ORIGINAL.run(&connection).unwrap();
 
-        // Verify it got added to the migrations table
+        let status = Migration::new(
+            "test",
+            &[
+                "CREATE TABLE test (a TEXT)",
+                "CREATE TABLE other (\n    b TEXT\n)",
+                "CREATE TABLE third (a TEXT)",
+            ],
+        )
+        .status(&connection)
+        .unwrap();
+
+        assert_eq!(status.applied, vec![0]);
+        assert_eq!(status.pending, vec![2]);
+        assert_eq!(status.mismatched.len(), 1);
+        assert_eq!(status.mismatched[0].step, 1);
         assert_eq!(
-            &connection
-                .prepare("SELECT (migration) FROM migrations")
-                .unwrap()
-                .rows::<String>()
-                .unwrap()[..],
-            migration.migrations
+            status.mismatched[0].diff,
+            "  CREATE TABLE other (\n-     a TEXT\n+     b TEXT\n  )"
         );
+        assert!(status.unknown.is_empty());
+    }
 
-        // Add another step to the migration and run it again
-        migration.migrations = &[
-            indoc! {"
-                CREATE TABLE test1 (
-                    a TEXT,
-                    b TEXT
-                );"},
-            indoc! {"
-                CREATE TABLE test2 (
-                    c TEXT,
-                    d TEXT
-                );"},
-        ];
-        migration.run(&connection).unwrap();
+    #[test]
+    fn dry_run_is_always_rolled_back() {
+        let connection = Connection::open_memory("dry_run_is_always_rolled_back");
 
-        // Verify it is also added to the migrations table
+        const MIGRATION: Migration = Migration::new(
+            "test",
+            &[
+                "CREATE TABLE test (a TEXT)",
+                "INSERT INTO test (a) VALUES ('row')",
+                "INSERT INTO missing (a) VALUES ('row')",
+            ],
+        );
+
+        let dry_run = MIGRATION.dry_run(&connection).unwrap();
+        assert_eq!(dry_run.applied, vec![0, 1]);
+        assert!(dry_run
+            .error
+            .is_some_and(|error| error.contains("no such table: missing")));
         assert_eq!(
-            &connection
-                .prepare("SELECT (migration) FROM migrations")
+            connection
+                .prepare("SELECT COUNT(*) FROM sqlite_master")
                 .unwrap()
-                .rows::<String>()
-                .unwrap()[..],
-            migration.migrations
+                .row::<usize>()
+                .unwrap(),
+            0
         );
     }
 
     #[test]
-    fn test_migration_setup_works() {
-        let connection = Connection::open_memory("migration_setup_works");
+    fn dry_runs_report_recovery_without_backing_up() {
+        fn unreachable_callback(_: &Connection, _: &ChangedMigration) -> Result<()> {
+            panic!("Dry runs must not call the changed policy's callback")
+        }
+
+        let directory = TempDir::new("dry_run_recovery");
+        let connection = Connection::open_file(directory.join("db.sqlite").to_str().unwrap());
+        Migration::new("test", &["CREATE TABLE test (a TEXT)"])
+            .run(&connection)
+            .unwrap();
+
+        const CHANGED: Migration = Migration::new(
+            "test",
+            &["CREATE TABLE test (b TEXT)", "CREATE TABLE other (a TEXT)"],
+        );
+        let dry_run = CHANGED
+            .with_changed_policy(ChangedPolicy::DropDomainAndReapply)
+            .dry_run(&connection)
+            .unwrap();
+        assert_eq!(dry_run.applied, vec![0, 1]);
+        assert_eq!(dry_run.mismatched[0].step, 0);
+        assert_eq!(dry_run.recovery, Some(Recovery::Reapply));
+        assert_eq!(dry_run.error, None);
 
+        let dry_run = CHANGED.dry_run(&connection).unwrap();
+        assert_eq!(dry_run.recovery, Some(Recovery::Fail));
+        assert!(dry_run.error.is_some());
+
+        let dry_run = CHANGED
+            .with_changed_policy(ChangedPolicy::Callback(unreachable_callback))
+            .dry_run(&connection)
+            .unwrap();
+        assert_eq!(dry_run.recovery, Some(Recovery::Callback));
+        assert!(dry_run.applied.is_empty());
+
+        connection.exec("SELECT a FROM test").unwrap();
+        assert_eq!(fs::read_dir(directory.path()).unwrap().count(), 1);
+        drop(connection);
+    }
+
+    #[test]
+    fn status_and_dry_runs_read_blob_era_migrations_tables() {
+        // Released versions stored strings as BLOBs and had no hash column
+        let connection = Connection::open_memory("status_reads_blob_era_tables");
         connection
-            .exec(indoc! {"CREATE TABLE IF NOT EXISTS migrations (
+            .exec(indoc! {"
+                CREATE TABLE migrations (
                     domain TEXT,
                     step INTEGER,
                     migration TEXT
-                );"})
-            .unwrap();
-
-        let mut store_completed_migration = connection
-            .prepare(indoc! {"
+                );
+                CREATE TABLE test (a TEXT);
                 INSERT INTO migrations (domain, step, migration)
-                VALUES (?, ?, ?)"})
+                VALUES (
+                    CAST('test' AS BLOB),
+                    0,
+                    CAST('CREATE TABLE test (a TEXT)' AS BLOB)
+                );"})
             .unwrap();
 
-        let domain = "test_domain";
-        for i in 0..5 {
-            // Create a table forcing a schema change
-            connection
-                .exec(format!("CREATE TABLE table{} ( test TEXT );", i))
-                .unwrap();
+        const MIGRATION: Migration = Migration::new(
+            "test",
+            &["CREATE TABLE test (a TEXT)", "CREATE TABLE other (a TEXT)"],
+        );
+        let status = MIGRATION.status(&connection).unwrap();
+        assert_eq!(status.applied, vec![0]);
+        assert_eq!(status.pending, vec![1]);
 
-            store_completed_migration
-                .bound((domain, i, i.to_string()))
-                .unwrap()
-                .run()
-                .unwrap();
-        }
+        let dry_run = MIGRATION.dry_run(&connection).unwrap();
+        assert_eq!(dry_run.applied, vec![1]);
+        assert_eq!(dry_run.error, None);
+        assert_eq!(MIGRATION.status(&connection).unwrap(), status);
     }
 
     #[test]
-    fn migrations_dont_rerun() {
-        let connection = Connection::open_memory("migrations_dont_rerun");
+    fn embedded_migrations_match_loaded_migrations() {
+        const EMBEDDED: Migration = Migration::new(
+            "workspace",
+            embed_migrations!("test_fixtures/migrations/workspace"),
+        );
+        let loaded = Migration::load_dir(
+            "workspace",
+            concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/test_fixtures/migrations/workspace"
+            ),
+        )
+        .unwrap();
+        assert_eq!(EMBEDDED.migrations, loaded.migrations);
+        assert_eq!(EMBEDDED.migrations.len(), 2);
 
-        // Create migration which clears a table
-        let migration = Migration::new("test", &["DELETE FROM test_table"]);
+        // Paths are read as string literals, so raw strings and escapes work as usual
+        const RAW: &[&str] = embed_migrations!(r#"test_fixtures/migrations/workspace"#);
+        const ESCAPED: &[&str] = embed_migrations!("test_fixtures\x2fmigrations/\u{77}orkspace");
+        assert_eq!(RAW, EMBEDDED.migrations);
+        assert_eq!(ESCAPED, EMBEDDED.migrations);
 
-        // Manually create the table for that migration with a row
+        let connection = Connection::open_memory("embedded_migrations_match_loaded_migrations");
+        EMBEDDED.run(&connection).unwrap();
+        loaded.run(&connection).unwrap();
         connection
-            .exec(indoc! {"
-            CREATE TABLE test_table (
-                test_column INTEGER
-            );
-            INSERT INTO test_table (test_column) VALUES (1)"})
+            .exec("INSERT INTO workspaces (path, timestamp) VALUES ('/path', 'now')")
             .unwrap();
+    }
+
+    #[test]
+    fn loading_a_directory_with_gaps_fails() {
+        let directory = TempDir::new("migration_gaps");
+        fs::write(directory.join("0001_init.sql"), "CREATE TABLE a (a TEXT)").unwrap();
+        fs::write(directory.join("0003_later.sql"), "CREATE TABLE b (b TEXT)").unwrap();
 
+        let error = Migration::load_dir("test", directory.path()).err().unwrap();
         assert_eq!(
-            connection
-                .prepare("SELECT * FROM test_table")
-                .unwrap()
-                .row::<usize>()
-                .unwrap(),
-            1
+            error.to_string(),
+            "Migration number 2 is missing before 0003_later.sql"
         );
 
-        // Run the migration verifying that the row got dropped
-        migration.run(&connection).unwrap();
+        fs::write(directory.join("0002_middle.sql"), "CREATE TABLE c (c TEXT)").unwrap();
+        fs::write(directory.join("0002_again.sql"), "CREATE TABLE d (d TEXT)").unwrap();
+        assert!(Migration::load_dir("test", directory.path()).is_err());
+    }
+
+    #[test]
+    fn concurrent_runs_on_one_file_apply_each_step_once() {
+        fn slow_step(connection: &Connection) -> Result<()> {
+            thread::sleep(Duration::from_millis(50));
+            connection.exec("CREATE TABLE slow (a TEXT)")
+        }
+
+        const STEPS: &[Step] = &[
+            Step::Sql("CREATE TABLE test (a TEXT)"),
+            Step::Function {
+                name: "slow_step",
+                version: 1,
+                function: slow_step,
+            },
+        ];
+        const MIGRATION: Migration = Migration::with_steps("test", STEPS);
+
+        let directory = TempDir::new("migration_lock");
+        let path = directory.join("db.sqlite").to_string_lossy().into_owned();
+
+        let handles = (0..2)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || MIGRATION.run(&Connection::open(&path, true)?))
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+
+        let connection = Connection::open(&path, true).unwrap();
         assert_eq!(
             connection
-                .prepare("SELECT * FROM test_table")
+                .prepare("SELECT step FROM migrations ORDER BY step")
                 .unwrap()
                 .rows::<usize>()
                 .unwrap(),
-            Vec::new()
+            vec![0, 1]
         );
+        drop(connection);
+    }
 
-        // Recreate the dropped row
-        connection
-            .exec("INSERT INTO test_table (test_column) VALUES (2)")
+    #[test]
+    fn long_running_migrations_keep_their_lock() {
+        // Runs for longer than a claim may stand still, without holding the write lock
+        fn slower_than_stale_claims(connection: &Connection) -> Result<()> {
+            thread::sleep(Duration::from_secs(7));
+            connection.exec("CREATE TABLE slow (a TEXT)")
+        }
+
+        const STEPS: &[Step] = &[Step::Function {
+            name: "slower_than_stale_claims",
+            version: 1,
+            function: slower_than_stale_claims,
+        }];
+        const MIGRATION: Migration =
+            Migration::with_steps("test", STEPS).with_non_transactional_steps(&[0]);
+
+        let directory = TempDir::new("long_running_migration");
+        let path = directory.join("db.sqlite").to_string_lossy().into_owned();
+
+        let handles = (0..2)
+            .map(|index| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(100) * index);
+                    MIGRATION.run(&Connection::open(&path, true)?)
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn baseline_replaces_squashed_steps() {
+        const STEPS: &[&str] = &[
+            "CREATE TABLE test (a TEXT)",
+            "ALTER TABLE test ADD COLUMN b TEXT",
+            "CREATE INDEX test_b ON test (b)",
+        ];
+        const BASELINE: &str =
+            "CREATE TABLE test (a TEXT, b TEXT);\nCREATE INDEX test_b ON test (b);";
+        const SQUASHED: Migration =
+            Migration::new("test", &["CREATE TABLE later (c TEXT)"]).with_baseline(BASELINE, 3);
+
+        assert_eq!(Migration::new("test", STEPS).squash(3).unwrap(), BASELINE);
+
+        let existing = Connection::open_memory("baseline_replaces_squashed_steps_existing");
+        Migration::new("test", STEPS).run(&existing).unwrap();
+        SQUASHED.run(&existing).unwrap();
+
+        let fresh = Connection::open_memory("baseline_replaces_squashed_steps_fresh");
+        SQUASHED.run(&fresh).unwrap();
+
+        for connection in [&existing, &fresh] {
+            connection.exec("SELECT a, b FROM test").unwrap();
+            connection.exec("SELECT c FROM later").unwrap();
+            let status = SQUASHED.status(connection).unwrap();
+            assert_eq!(status.applied, vec![0, 1, 2, 3]);
+        }
+
+        let partial = Connection::open_memory("baseline_replaces_squashed_steps_partial");
+        Migration::new("test", &STEPS[..1]).run(&partial).unwrap();
+        assert!(SQUASHED.run(&partial).is_err());
+    }
+
+    #[test]
+    fn foreign_application_ids_are_refused() {
+        const APPLICATION_ID: i32 = 0x5a45_4421;
+
+        let connection = Connection::open_memory("foreign_application_ids_are_refused");
+        Migration::new("test", &["CREATE TABLE test (a TEXT)"])
+            .run(&connection)
             .unwrap();
+        Migration::claim_application_id(&connection, APPLICATION_ID).unwrap();
+        Migration::claim_application_id(&connection, APPLICATION_ID).unwrap();
+        assert!(Migration::claim_application_id(&connection, 1)
+            .unwrap_err()
+            .to_string()
+            .contains("expected application id 0x00000001, found 0x5a454421"));
 
-        // Run the same migration again and verify that the table was left unchanged
-        migration.run(&connection).unwrap();
+        let unmarked = Connection::open_memory("foreign_application_ids_are_refused_unmarked");
+        unmarked.exec("CREATE TABLE other (a TEXT)").unwrap();
+        assert!(Migration::claim_application_id(&unmarked, APPLICATION_ID).is_err());
+
+        Migration::update_user_version(&connection).unwrap();
         assert_eq!(
             connection
-                .prepare("SELECT * FROM test_table")
+                .prepare("PRAGMA user_version")
                 .unwrap()
                 .row::<usize>()
                 .unwrap(),
-            2
+            1
         );
     }
 
     #[test]
-    fn changed_migration_fails() {
-        let connection = Connection::open_memory("changed_migration_fails");
-
-        // Create a migration with two steps and run it
-        Migration::new(
-            "test migration",
+    fn every_upgrade_path_is_tested() {
+        const MIGRATION: Migration = Migration::new(
+            "test",
             &[
-                indoc! {"
-                CREATE TABLE test (
-                    col INTEGER
-                )"},
-                indoc! {"
-                INSERT INTO test (col) VALUES (1)"},
+                "CREATE TABLE test (name TEXT)",
+                "ALTER TABLE test ADD COLUMN upper_name TEXT",
+                "UPDATE test SET upper_name = upper(name)",
             ],
-        )
-        .run(&connection)
-        .unwrap();
+        );
 
-        // Create another migration with the same domain but different steps
-        let second_migration_result = Migration::new(
-            "test migration",
-            &[
-                indoc! {"
-                CREATE TABLE test (
-                    color INTEGER
-                )"},
-                indoc! {"
-                INSERT INTO test (color) VALUES (1)"},
-            ],
-        )
-        .run(&connection);
+        let mut seeded = Vec::new();
+        MIGRATION
+            .test_upgrades(
+                |connection, applied_steps| {
+                    seeded.push(applied_steps);
+                    if applied_steps > 0 {
+                        connection.exec("INSERT INTO test (name) VALUES ('old')")?;
+                    }
+                    Ok(())
+                },
+                |connection, applied_steps| {
+                    let upper_names = connection
+                        .prepare("SELECT upper_name FROM test")?
+                        .rows::<Option<String>>()?;
+                    match applied_steps {
+                        0 => assert!(upper_names.is_empty()),
+                        // Rows inserted after the update step aren't backfilled
+                        3 => assert_eq!(upper_names, vec![None]),
+                        _ => assert_eq!(upper_names, vec![Some("OLD".to_string())]),
+                    }
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(seeded, vec![0, 1, 2, 3]);
 
-        // Verify new migration returns error when run
-        assert!(second_migration_result.is_err())
+        let error = MIGRATION
+            .test_upgrades(
+                |connection, applied_steps| match applied_steps {
+                    2 => connection.exec("DROP TABLE test"),
+                    _ => Ok(()),
+                },
+                |_, _| Ok(()),
+            )
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Upgrading test from step 2 failed");
     }
 }
@@ -0,0 +1,340 @@
+// Typed access to the pragmas applications commonly configure. Setters read the value back
+// afterwards and error if sqlite ignored it, which it does silently for unknown values and
+// for settings it can't apply, such as switching an in memory database to WAL or toggling
+// foreign keys inside a transaction.
+
+use std::{fmt, time::Duration};
+
+use anyhow::{anyhow, Result};
+
+use crate::{bindable::Column, connection::Connection};
+
+macro_rules! pragma_enum {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident => $value:literal),+ $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub enum $name {
+            $($variant),+
+        }
+
+        impl $name {
+            const VARIANTS: &'static [($name, &'static str)] = &[$(($name::$variant, $value)),+];
+
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $($name::$variant => $value),+
+                }
+            }
+
+            /// Parses the value reported by sqlite, which is either the name or the
+            /// variant's position in the list
+            fn parse(value: &str) -> Result<Self> {
+                Self::VARIANTS
+                    .iter()
+                    .enumerate()
+                    .find(|(index, (_, name))| {
+                        name.eq_ignore_ascii_case(value) || index.to_string() == value
+                    })
+                    .map(|(_, (variant, _))| *variant)
+                    .ok_or_else(|| {
+                        anyhow!("Unknown {} value {:?}", stringify!($name), value)
+                    })
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+
+        impl PragmaValue for $name {
+            fn sql(&self) -> String {
+                self.as_str().to_string()
+            }
+        }
+    };
+}
+
+pragma_enum!(
+    JournalMode {
+        Delete => "DELETE",
+        Truncate => "TRUNCATE",
+        Persist => "PERSIST",
+        Memory => "MEMORY",
+        Wal => "WAL",
+        Off => "OFF",
+    }
+);
+
+pragma_enum!(
+    Synchronous {
+        Off => "OFF",
+        Normal => "NORMAL",
+        Full => "FULL",
+        Extra => "EXTRA",
+    }
+);
+
+pragma_enum!(
+    TempStore {
+        Default => "DEFAULT",
+        File => "FILE",
+        Memory => "MEMORY",
+    }
+);
+
+pragma_enum!(
+    AutoVacuum {
+        None => "NONE",
+        Full => "FULL",
+        Incremental => "INCREMENTAL",
+    }
+);
+
+pragma_enum!(
+    LockingMode {
+        Normal => "NORMAL",
+        Exclusive => "EXCLUSIVE",
+    }
+);
+
+impl Connection {
+    pub fn journal_mode(&self) -> Result<JournalMode> {
+        JournalMode::parse(&self.pragma::<String>("journal_mode")?)
+    }
+
+    pub fn set_journal_mode(&self, journal_mode: JournalMode) -> Result<()> {
+        self.set_pragma("journal_mode", journal_mode, Self::journal_mode)
+    }
+
+    pub fn synchronous(&self) -> Result<Synchronous> {
+        Synchronous::parse(&self.pragma::<String>("synchronous")?)
+    }
+
+    pub fn set_synchronous(&self, synchronous: Synchronous) -> Result<()> {
+        self.set_pragma("synchronous", synchronous, Self::synchronous)
+    }
+
+    pub fn temp_store(&self) -> Result<TempStore> {
+        TempStore::parse(&self.pragma::<String>("temp_store")?)
+    }
+
+    pub fn set_temp_store(&self, temp_store: TempStore) -> Result<()> {
+        self.set_pragma("temp_store", temp_store, Self::temp_store)
+    }
+
+    /// Changing between None and the other modes only takes effect after a vacuum, see
+    /// `set_auto_vacuum` in the vacuum module
+    pub fn auto_vacuum(&self) -> Result<AutoVacuum> {
+        AutoVacuum::parse(&self.pragma::<String>("auto_vacuum")?)
+    }
+
+    pub fn locking_mode(&self) -> Result<LockingMode> {
+        LockingMode::parse(&self.pragma::<String>("locking_mode")?)
+    }
+
+    pub fn set_locking_mode(&self, locking_mode: LockingMode) -> Result<()> {
+        self.set_pragma("locking_mode", locking_mode, Self::locking_mode)
+    }
+
+    pub fn foreign_keys(&self) -> Result<bool> {
+        Ok(self.pragma::<i32>("foreign_keys")? != 0)
+    }
+
+    pub fn set_foreign_keys(&self, enabled: bool) -> Result<()> {
+        self.set_pragma("foreign_keys", enabled, Self::foreign_keys)
+    }
+
+    /// The page cache size. Positive values are a number of pages, negative values are a
+    /// size in KiB
+    pub fn cache_size(&self) -> Result<i64> {
+        self.pragma("cache_size")
+    }
+
+    pub fn set_cache_size(&self, cache_size: i64) -> Result<()> {
+        self.set_pragma("cache_size", cache_size, Self::cache_size)
+    }
+
+    /// The maximum number of bytes of the database file to memory map
+    pub fn mmap_size(&self) -> Result<i64> {
+        self.pragma("mmap_size")
+    }
+
+    pub fn set_mmap_size(&self, mmap_size: i64) -> Result<()> {
+        self.set_pragma("mmap_size", mmap_size, Self::mmap_size)
+    }
+
+    /// How long to retry when another connection holds a conflicting lock
+    pub fn busy_timeout(&self) -> Result<Duration> {
+        Ok(Duration::from_millis(
+            self.pragma::<i64>("busy_timeout")?.max(0) as u64,
+        ))
+    }
+
+    pub fn set_busy_timeout(&self, busy_timeout: Duration) -> Result<()> {
+        self.set_pragma("busy_timeout", busy_timeout, Self::busy_timeout)
+    }
+
+    /// The number of frames the WAL may reach before a commit checkpoints it. Zero or less
+    /// disables automatic checkpoints. Setting it replaces any hook set with `set_wal_hook`
+    pub fn wal_autocheckpoint(&self) -> Result<i32> {
+        self.pragma("wal_autocheckpoint")
+    }
+
+    pub fn set_wal_autocheckpoint(&self, frames: i32) -> Result<()> {
+        self.set_pragma("wal_autocheckpoint", frames, Self::wal_autocheckpoint)
+    }
+
+    pub fn page_size(&self) -> Result<usize> {
+        self.pragma("page_size")
+    }
+
+    /// The number of pages in the database, including free pages
+    pub fn page_count(&self) -> Result<usize> {
+        self.pragma("page_count")
+    }
+
+    /// The number of unused pages which a vacuum would free
+    pub fn freelist_count(&self) -> Result<usize> {
+        self.pragma("freelist_count")
+    }
+
+    pub fn application_id(&self) -> Result<i32> {
+        self.pragma("application_id")
+    }
+
+    pub fn set_application_id(&self, application_id: i32) -> Result<()> {
+        self.set_pragma("application_id", application_id, Self::application_id)
+    }
+
+    pub fn user_version(&self) -> Result<i32> {
+        self.pragma("user_version")
+    }
+
+    pub fn set_user_version(&self, user_version: i32) -> Result<()> {
+        self.set_pragma("user_version", user_version, Self::user_version)
+    }
+
+    /// Runs `PRAGMA integrity_check`, returning the problems found as an error
+    pub fn integrity_check(&self) -> Result<()> {
+        let problems = self.prepare("PRAGMA integrity_check")?.rows::<String>()?;
+        if problems != ["ok"] {
+            return Err(anyhow!("Integrity check failed:\n{}", problems.join("\n")));
+        }
+        Ok(())
+    }
+
+    fn pragma<T: Column>(&self, name: &str) -> Result<T> {
+        self.prepare(format!("PRAGMA {}", name))?.row::<T>()
+    }
+
+    /// Sets the pragma, then reads it back and errors if sqlite didn't apply the value
+    fn set_pragma<T>(&self, name: &str, value: T, read: fn(&Self) -> Result<T>) -> Result<()>
+    where
+        T: PragmaValue + fmt::Debug + PartialEq,
+    {
+        // Some pragmas report the new value as a row, so the statement is stepped to the end
+        // rather than run with exec
+        self.prepare(format!("PRAGMA {} = {}", name, value.sql()))?
+            .rows::<Option<String>>()?;
+
+        let applied = read(self)?;
+        if applied != value {
+            return Err(anyhow!(
+                "Sqlite ignored PRAGMA {} = {}, the value is still {:?}",
+                name,
+                value.sql(),
+                applied
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A value which can be written into a pragma statement
+trait PragmaValue {
+    fn sql(&self) -> String;
+}
+
+impl PragmaValue for bool {
+    fn sql(&self) -> String {
+        if *self { "ON" } else { "OFF" }.to_string()
+    }
+}
+
+impl PragmaValue for i32 {
+    fn sql(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl PragmaValue for i64 {
+    fn sql(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl PragmaValue for Duration {
+    fn sql(&self) -> String {
+        self.as_millis().to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        connection::Connection,
+        pragma::{JournalMode, Synchronous, TempStore},
+        test_support::TempDir,
+    };
+
+    #[test]
+    fn pragmas_round_trip() {
+        let connection = Connection::open_memory("pragmas_round_trip");
+
+        connection.set_synchronous(Synchronous::Full).unwrap();
+        assert_eq!(connection.synchronous().unwrap(), Synchronous::Full);
+        connection.set_temp_store(TempStore::Memory).unwrap();
+        assert_eq!(connection.temp_store().unwrap(), TempStore::Memory);
+        connection.set_foreign_keys(true).unwrap();
+        assert!(connection.foreign_keys().unwrap());
+        connection.set_cache_size(-4096).unwrap();
+        assert_eq!(connection.cache_size().unwrap(), -4096);
+        connection
+            .set_busy_timeout(Duration::from_millis(250))
+            .unwrap();
+        assert_eq!(
+            connection.busy_timeout().unwrap(),
+            Duration::from_millis(250)
+        );
+        connection.set_user_version(3).unwrap();
+        assert_eq!(connection.user_version().unwrap(), 3);
+    }
+
+    #[test]
+    fn ignored_pragmas_are_errors() {
+        let connection = Connection::open_memory("ignored_pragmas_are_errors");
+        assert_eq!(connection.journal_mode().unwrap(), JournalMode::Memory);
+        assert!(connection.set_journal_mode(JournalMode::Wal).is_err());
+
+        connection.exec("BEGIN").unwrap();
+        assert!(connection
+            .set_foreign_keys(!connection.foreign_keys().unwrap())
+            .is_err());
+        connection.exec("COMMIT").unwrap();
+    }
+
+    #[test]
+    fn journal_mode_can_be_changed_on_files() {
+        let directory = TempDir::new("journal_mode");
+        let connection = Connection::open_file(directory.join("db.sqlite").to_str().unwrap());
+
+        connection.set_journal_mode(JournalMode::Wal).unwrap();
+        assert_eq!(connection.journal_mode().unwrap(), JournalMode::Wal);
+
+        drop(connection);
+    }
+}
@@ -1,14 +1,22 @@
-use anyhow::Result;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
 
 use crate::connection::Connection;
 
+/// How long an exclusive transaction waits for other connections to release the database
+const EXCLUSIVE_TRANSACTION_TIMEOUT: Duration = Duration::from_secs(30);
+
 impl Connection {
     // Run a set of commands within the context of a `SAVEPOINT name`. If the callback
     // returns Ok(None) or Err(_), the savepoint will be rolled back. Otherwise, the save
     // point is released.
-    pub fn with_savepoint<F, R>(&mut self, name: impl AsRef<str>, f: F) -> Result<Option<R>>
+    pub fn with_savepoint<F, R>(&self, name: impl AsRef<str>, f: F) -> Result<Option<R>>
     where
-        F: FnOnce(&mut Connection) -> Result<Option<R>>,
+        F: FnOnce(&Connection) -> Result<Option<R>>,
     {
         let name = name.as_ref().to_owned();
         self.exec(format!("SAVEPOINT {}", &name))?;
@@ -24,6 +32,43 @@ impl Connection {
         }
         result
     }
+
+    // Run a set of commands within a `BEGIN EXCLUSIVE` transaction. The transaction is
+    // committed if the callback succeeds and rolled back otherwise. While another connection
+    // holds the database, beginning the transaction is retried for up to 30 seconds. Unlike
+    // savepoints, exclusive transactions can't be nested
+    pub fn with_exclusive_transaction<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&Connection) -> Result<R>,
+    {
+        self.begin_retrying("BEGIN EXCLUSIVE", EXCLUSIVE_TRANSACTION_TIMEOUT)
+            .context("Could not lock the database")?;
+        match f(self) {
+            Ok(result) => {
+                self.exec("COMMIT")?;
+                Ok(result)
+            }
+            Err(error) => {
+                self.exec("ROLLBACK").ok();
+                Err(error)
+            }
+        }
+    }
+
+    /// Runs a `BEGIN` statement, retrying while another connection holds a conflicting lock
+    /// until the timeout has elapsed
+    pub(crate) fn begin_retrying(&self, begin: &str, timeout: Duration) -> Result<()> {
+        let started = Instant::now();
+        loop {
+            match self.exec(begin) {
+                Ok(()) => return Ok(()),
+                Err(_) if self.is_busy() && started.elapsed() < timeout => {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -34,7 +79,7 @@ mod tests {
 
     #[test]
     fn test_nested_savepoints() -> Result<()> {
-        let mut connection = Connection::open_memory("nested_savepoints");
+        let connection = Connection::open_memory("nested_savepoints");
 
         connection
             .exec(indoc! {"
@@ -0,0 +1,584 @@
+// Introspection of a database's schema into a structure which can be compared, so tests can
+// assert that migrating a fresh database and upgrading an old one end up in the same place.
+// Tables and indexes are read column by column through the table_info pragmas, along with
+// each table's foreign keys, while views and triggers are compared by their normalized sql.
+// The WHERE clause of a partial index is only available in its sql, so it is cut out of there.
+// Sqlite's internal objects and the migrations bookkeeping tables are left out.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use indoc::indoc;
+
+use crate::{connection::Connection, migrations::normalize_migration};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Schema {
+    pub tables: BTreeMap<String, Table>,
+    pub indexes: BTreeMap<String, Index>,
+    /// Normalized sql for each view
+    pub views: BTreeMap<String, String>,
+    /// Normalized sql for each trigger
+    pub triggers: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Table {
+    pub columns: Vec<TableColumn>,
+    /// Sorted so that the order the keys were declared in doesn't matter
+    pub foreign_keys: Vec<ForeignKey>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableColumn {
+    pub name: String,
+    pub declared_type: String,
+    pub not_null: bool,
+    pub default: Option<String>,
+    /// Position of the column within the primary key starting from 1, or 0 if it isn't part
+    /// of the primary key
+    pub primary_key: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ForeignKey {
+    pub columns: Vec<String>,
+    pub parent: String,
+    /// Columns referenced in the parent table, or empty if the key refers to the parent's
+    /// primary key implicitly
+    pub parent_columns: Vec<String>,
+    pub on_update: String,
+    pub on_delete: String,
+}
+
+impl std::fmt::Display for ForeignKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "({}) REFERENCES {}",
+            self.columns.join(", "),
+            self.parent
+        )?;
+        if !self.parent_columns.is_empty() {
+            write!(f, " ({})", self.parent_columns.join(", "))?;
+        }
+        if self.on_update != "NO ACTION" {
+            write!(f, " ON UPDATE {}", self.on_update)?;
+        }
+        if self.on_delete != "NO ACTION" {
+            write!(f, " ON DELETE {}", self.on_delete)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Index {
+    pub table: String,
+    pub columns: Vec<IndexColumn>,
+    pub unique: bool,
+    /// Normalized WHERE clause of a partial index, without the WHERE keyword
+    pub filter: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexColumn {
+    /// Column name, or `<expression>` for indexed expressions
+    pub name: String,
+    pub descending: bool,
+    pub collation: String,
+}
+
+impl std::fmt::Display for IndexColumn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if self.collation != "BINARY" {
+            write!(f, " COLLATE {}", self.collation)?;
+        }
+        if self.descending {
+            write!(f, " DESC")?;
+        }
+        Ok(())
+    }
+}
+
+impl Connection {
+    /// Reads the tables, indexes, views and triggers in the main database
+    pub fn schema(&self) -> Result<Schema> {
+        let objects = self
+            .prepare(indoc! {"
+                SELECT type, name, tbl_name, sql FROM sqlite_master
+                WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_%'
+                    AND name NOT IN ('migrations', 'migration_lock')
+                ORDER BY name"})?
+            .rows::<(String, String, String, String)>()?;
+
+        let mut schema = Schema::default();
+        for (object_type, name, table, sql) in objects {
+            match object_type.as_str() {
+                "table" => {
+                    let table = self.table_schema(&name)?;
+                    schema.tables.insert(name, table);
+                }
+                "index" => {
+                    let index = self.index_schema(&name, table, &sql)?;
+                    schema.indexes.insert(name, index);
+                }
+                "view" => {
+                    schema.views.insert(name, normalize_migration(&sql));
+                }
+                "trigger" => {
+                    schema.triggers.insert(name, normalize_migration(&sql));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(schema)
+    }
+
+    fn table_schema(&self, table: &str) -> Result<Table> {
+        let columns = self
+            .prepare(indoc! {r#"
+                SELECT name, type, "notnull", dflt_value, pk FROM pragma_table_info(CAST(? AS TEXT))
+                ORDER BY cid"#})?
+            .bound(table)?
+            .rows::<((String, String), (usize, Option<String>, usize))>()?
+            .into_iter()
+            .map(
+                |((name, declared_type), (not_null, default, primary_key))| TableColumn {
+                    name,
+                    declared_type,
+                    not_null: not_null != 0,
+                    default,
+                    primary_key,
+                },
+            )
+            .collect();
+
+        let mut foreign_keys = Vec::<ForeignKey>::new();
+        let references = self
+            .prepare(indoc! {r#"
+                SELECT id, "from", "table", "to", on_update, on_delete
+                FROM pragma_foreign_key_list(CAST(? AS TEXT))
+                ORDER BY id, seq"#})?
+            .bound(table)?
+            .rows::<((usize, String, String), (Option<String>, String, String))>()?;
+        let mut last_id = None;
+        for ((id, column, parent), (parent_column, on_update, on_delete)) in references {
+            if last_id != Some(id) {
+                last_id = Some(id);
+                foreign_keys.push(ForeignKey {
+                    columns: Vec::new(),
+                    parent,
+                    parent_columns: Vec::new(),
+                    on_update,
+                    on_delete,
+                });
+            }
+            if let Some(foreign_key) = foreign_keys.last_mut() {
+                foreign_key.columns.push(column);
+                // Null when the parent's primary key is referenced implicitly
+                foreign_key.parent_columns.extend(parent_column);
+            }
+        }
+        foreign_keys.sort();
+
+        Ok(Table {
+            columns,
+            foreign_keys,
+        })
+    }
+
+    fn index_schema(&self, index: &str, table: String, sql: &str) -> Result<Index> {
+        // index_xinfo also lists the rowid and other auxiliary columns, which aren't keys
+        let columns = self
+            .prepare(indoc! {r#"
+                SELECT name, "desc", coll FROM pragma_index_xinfo(CAST(? AS TEXT))
+                WHERE key ORDER BY seqno"#})?
+            .bound(index)?
+            .rows::<(Option<String>, usize, String)>()?
+            .into_iter()
+            .map(|(name, descending, collation)| IndexColumn {
+                // Expression columns have no name
+                name: name.unwrap_or_else(|| "<expression>".to_string()),
+                descending: descending != 0,
+                collation,
+            })
+            .collect();
+        let (unique, partial) = self
+            .prepare(indoc! {r#"
+                SELECT "unique", partial FROM pragma_index_list(CAST(? AS TEXT))
+                WHERE name = CAST(? AS TEXT)"#})?
+            .bound((table.as_str(), index))?
+            .row::<(usize, usize)>()?;
+
+        Ok(Index {
+            table,
+            columns,
+            unique: unique != 0,
+            filter: if partial != 0 {
+                index_filter(&normalize_migration(sql))
+            } else {
+                None
+            },
+        })
+    }
+}
+
+impl Schema {
+    /// Lists how another schema differs from this one, one difference per line, treating
+    /// this schema as the expected one. Returns an empty list if the schemas match
+    pub fn diff(&self, other: &Schema) -> Vec<String> {
+        let mut differences = Vec::new();
+
+        diff_maps(
+            "table",
+            &self.tables,
+            &other.tables,
+            &mut differences,
+            diff_tables,
+        );
+        diff_maps(
+            "index",
+            &self.indexes,
+            &other.indexes,
+            &mut differences,
+            diff_indexes,
+        );
+        diff_maps(
+            "view",
+            &self.views,
+            &other.views,
+            &mut differences,
+            |name, a, b| diff_sql("view", name, a, b),
+        );
+        diff_maps(
+            "trigger",
+            &self.triggers,
+            &other.triggers,
+            &mut differences,
+            |name, a, b| diff_sql("trigger", name, a, b),
+        );
+
+        differences
+    }
+}
+
+/// Reports objects only present on one side, and the differences between the objects
+/// present on both
+fn diff_maps<T>(
+    kind: &str,
+    a: &BTreeMap<String, T>,
+    b: &BTreeMap<String, T>,
+    differences: &mut Vec<String>,
+    diff_object: impl Fn(&str, &T, &T) -> Vec<String>,
+) {
+    for (name, a_object) in a {
+        match b.get(name) {
+            Some(b_object) => differences.extend(diff_object(name, a_object, b_object)),
+            None => differences.push(format!("{} {} is missing", kind, name)),
+        }
+    }
+    for name in b.keys().filter(|name| !a.contains_key(*name)) {
+        differences.push(format!("{} {} was added", kind, name));
+    }
+}
+
+fn diff_tables(table: &str, a: &Table, b: &Table) -> Vec<String> {
+    let mut differences = Vec::new();
+
+    for a_column in &a.columns {
+        let Some(b_column) = b.columns.iter().find(|column| column.name == a_column.name) else {
+            differences.push(format!("column {}.{} is missing", table, a_column.name));
+            continue;
+        };
+
+        let name = format!("{}.{}", table, a_column.name);
+        if a_column.declared_type != b_column.declared_type {
+            differences.push(format!(
+                "column {} has type {:?}, not {:?}",
+                name, b_column.declared_type, a_column.declared_type
+            ));
+        }
+        if a_column.not_null != b_column.not_null {
+            differences.push(format!(
+                "column {} is {}",
+                name,
+                if b_column.not_null {
+                    "NOT NULL"
+                } else {
+                    "nullable"
+                }
+            ));
+        }
+        if a_column.default != b_column.default {
+            differences.push(format!(
+                "column {} defaults to {}, not {}",
+                name,
+                b_column.default.as_deref().unwrap_or("NULL"),
+                a_column.default.as_deref().unwrap_or("NULL")
+            ));
+        }
+        if a_column.primary_key != b_column.primary_key {
+            differences.push(format!(
+                "column {} has primary key position {}, not {}",
+                name, b_column.primary_key, a_column.primary_key
+            ));
+        }
+    }
+
+    for b_column in &b.columns {
+        if !a.columns.iter().any(|column| column.name == b_column.name) {
+            differences.push(format!("column {}.{} was added", table, b_column.name));
+        }
+    }
+
+    let a_order = a.columns.iter().map(|column| &column.name);
+    let b_order = b.columns.iter().map(|column| &column.name);
+    if differences.is_empty() && !a_order.eq(b_order) {
+        differences.push(format!(
+            "columns of table {} are in a different order",
+            table
+        ));
+    }
+
+    for foreign_key in &a.foreign_keys {
+        if !b.foreign_keys.contains(foreign_key) {
+            differences.push(format!("foreign key {} {} is missing", table, foreign_key));
+        }
+    }
+    for foreign_key in &b.foreign_keys {
+        if !a.foreign_keys.contains(foreign_key) {
+            differences.push(format!("foreign key {} {} was added", table, foreign_key));
+        }
+    }
+
+    differences
+}
+
+fn diff_indexes(index: &str, a: &Index, b: &Index) -> Vec<String> {
+    let mut differences = Vec::new();
+    if a.table != b.table {
+        differences.push(format!(
+            "index {} is on table {}, not {}",
+            index, b.table, a.table
+        ));
+    }
+    if a.columns != b.columns {
+        let columns = |index: &Index| {
+            index
+                .columns
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        differences.push(format!(
+            "index {} covers ({}), not ({})",
+            index,
+            columns(b),
+            columns(a)
+        ));
+    }
+    if a.unique != b.unique {
+        differences.push(format!(
+            "index {} is {}unique",
+            index,
+            if b.unique { "" } else { "not " }
+        ));
+    }
+    if a.filter != b.filter {
+        differences.push(format!(
+            "index {} is filtered by {}, not {}",
+            index,
+            b.filter.as_deref().unwrap_or("nothing"),
+            a.filter.as_deref().unwrap_or("nothing")
+        ));
+    }
+    differences
+}
+
+/// Cuts the WHERE clause out of a CREATE INDEX statement. The clause is whatever follows the
+/// parenthesized column list, which is the first parenthesis outside of a quoted name
+fn index_filter(sql: &str) -> Option<String> {
+    let mut depth = 0;
+    let mut quote = None;
+    for (position, char) in sql.char_indices() {
+        match (quote, char) {
+            (Some(open), _) if char == open => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"' | '`') => quote = Some(char),
+            (None, '[') => quote = Some(']'),
+            (None, '(') => depth += 1,
+            (None, ')') => {
+                depth -= 1;
+                if depth == 0 {
+                    let rest = sql[position + 1..].trim();
+                    let filter = rest
+                        .get(..5)
+                        .filter(|keyword| keyword.eq_ignore_ascii_case("WHERE"))
+                        .map(|_| rest[5..].trim().to_string());
+                    return filter.filter(|filter| !filter.is_empty());
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn diff_sql(kind: &str, name: &str, a: &str, b: &str) -> Vec<String> {
+    if a == b {
+        Vec::new()
+    } else {
+        vec![format!("{} {} changed from {} to {}", kind, name, a, b)]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use indoc::indoc;
+
+    use crate::{connection::Connection, migrations::Migration};
+
+    #[test]
+    fn schema_reads_every_object() {
+        let connection = Connection::open_memory("schema_reads_every_object");
+        connection
+            .exec(indoc! {"
+                CREATE TABLE test (
+                    id INTEGER PRIMARY KEY,
+                    a TEXT NOT NULL DEFAULT 'a'
+                );
+                CREATE UNIQUE INDEX test_a ON test (a);
+                CREATE VIEW test_view AS SELECT a FROM test;
+                CREATE TRIGGER test_trigger AFTER INSERT ON test BEGIN
+                    DELETE FROM test WHERE id < 0;
+                END;"})
+            .unwrap();
+
+        let schema = connection.schema().unwrap();
+        let columns = &schema.tables["test"].columns;
+        assert_eq!(columns.len(), 2);
+        assert_eq!(columns[0].primary_key, 1);
+        assert!(columns[1].not_null);
+        assert_eq!(columns[1].default.as_deref(), Some("'a'"));
+        assert_eq!(schema.indexes["test_a"].columns[0].to_string(), "a");
+        assert!(schema.indexes["test_a"].unique);
+        assert_eq!(schema.indexes["test_a"].filter, None);
+        assert_eq!(
+            schema.views["test_view"],
+            "CREATE VIEW test_view AS SELECT a FROM test"
+        );
+        assert!(schema.triggers.contains_key("test_trigger"));
+    }
+
+    #[test]
+    fn upgraded_and_fresh_schemas_are_diffed() {
+        const OLD: Migration = Migration::new("test", &["CREATE TABLE test (a TEXT)"]);
+        const NEW: Migration = Migration::new(
+            "test",
+            &[
+                "CREATE TABLE test (a TEXT)",
+                "ALTER TABLE test ADD COLUMN b INTEGER",
+            ],
+        );
+
+        let upgraded = Connection::open_memory("upgraded_and_fresh_schemas_upgraded");
+        OLD.run(&upgraded).unwrap();
+        NEW.run(&upgraded).unwrap();
+
+        let fresh = Connection::open_memory("upgraded_and_fresh_schemas_fresh");
+        NEW.run(&fresh).unwrap();
+        assert!(fresh
+            .schema()
+            .unwrap()
+            .diff(&upgraded.schema().unwrap())
+            .is_empty());
+
+        fresh
+            .exec("CREATE INDEX test_b ON test (b); DROP TABLE test; CREATE TABLE test (a INTEGER)")
+            .unwrap();
+        assert_eq!(
+            upgraded.schema().unwrap().diff(&fresh.schema().unwrap()),
+            vec![
+                "column test.a has type \"INTEGER\", not \"TEXT\"",
+                "column test.b is missing",
+            ]
+        );
+    }
+
+    #[test]
+    fn partial_and_ordered_indexes_are_diffed() {
+        let create = |name: &str, index: &str| {
+            let connection = Connection::open_memory(name);
+            connection
+                .exec(format!(
+                    "CREATE TABLE test (a TEXT, \"(b)\" INTEGER); {}",
+                    index
+                ))
+                .unwrap();
+            connection.schema().unwrap()
+        };
+
+        let full = create("partial_indexes_full", "CREATE INDEX test_a ON test (a)");
+        let partial = create(
+            "partial_indexes_partial",
+            "CREATE INDEX test_a ON test (a) WHERE \"(b)\" > 0",
+        );
+        assert_eq!(
+            partial.indexes["test_a"].filter.as_deref(),
+            Some("\"(b)\" > 0")
+        );
+        assert_eq!(
+            full.diff(&partial),
+            vec!["index test_a is filtered by \"(b)\" > 0, not nothing"]
+        );
+
+        let ordered = create(
+            "partial_indexes_ordered",
+            "CREATE INDEX test_a ON test (a COLLATE NOCASE DESC)",
+        );
+        assert_eq!(
+            full.diff(&ordered),
+            vec!["index test_a covers (a COLLATE NOCASE DESC), not (a)"]
+        );
+    }
+
+    #[test]
+    fn foreign_keys_are_diffed_and_lock_rows_ignored() {
+        let create = |name: &str, child: &str| {
+            let connection = Connection::open_memory(name);
+            connection
+                .exec(format!(
+                    "CREATE TABLE parent (id INTEGER PRIMARY KEY); {}",
+                    child
+                ))
+                .unwrap();
+            connection.schema().unwrap()
+        };
+
+        let fresh = create(
+            "foreign_keys_fresh",
+            "CREATE TABLE child (parent_id INTEGER REFERENCES parent (id) ON DELETE CASCADE)",
+        );
+        let upgraded = create(
+            "foreign_keys_upgraded",
+            indoc! {"
+                CREATE TABLE child (parent_id INTEGER REFERENCES parent);
+                CREATE TABLE migration_lock (owner TEXT, heartbeat INTEGER);"},
+        );
+        assert_eq!(
+            fresh.tables["child"].foreign_keys[0].to_string(),
+            "(parent_id) REFERENCES parent (id) ON DELETE CASCADE"
+        );
+        assert_eq!(
+            fresh.diff(&upgraded),
+            vec![
+                "foreign key child (parent_id) REFERENCES parent (id) ON DELETE CASCADE is missing",
+                "foreign key child (parent_id) REFERENCES parent was added",
+            ]
+        );
+    }
+}
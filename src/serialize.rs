@@ -0,0 +1,136 @@
+// Snapshots of a whole database as bytes. Serializing works on any schema, and loading a
+// snapshot back either copies it into the existing database so every connection to it sees
+// the change, or swaps this connection's view for a read only in memory copy.
+
+use std::{ffi::CString, ptr};
+
+use anyhow::{anyhow, Result};
+use libsqlite3_sys::*;
+
+use crate::connection::Connection;
+
+impl Connection {
+    /// Returns the contents of the schema, such as "main" or the name of an attached
+    /// database, in the sqlite file format
+    pub fn serialize(&self, schema: &str) -> Result<Vec<u8>> {
+        let schema_name = CString::new(schema)?;
+        unsafe {
+            let mut size = 0;
+            let data = sqlite3_serialize(self.sqlite3, schema_name.as_ptr(), &mut size, 0);
+            if data.is_null() {
+                self.last_error()?;
+                // Sqlite returns null without an error for databases with no pages yet
+                if size == 0 {
+                    return Ok(Vec::new());
+                }
+                return Err(anyhow!("Could not serialize schema {}", schema));
+            }
+
+            let bytes = std::slice::from_raw_parts(data, size as usize).to_vec();
+            sqlite3_free(data as *mut _);
+            Ok(bytes)
+        }
+    }
+
+    /// Loads a database produced by `serialize` into the schema.
+    /// If writable, the contents are copied into the existing database, so the file or the
+    /// shared memory database behind the schema is replaced and other connections to it
+    /// see the new contents. If read only, this connection's schema is instead detached
+    /// from whatever backed it and replaced with a read only copy of the bytes
+    pub fn deserialize(&self, schema: &str, bytes: &[u8], read_only: bool) -> Result<()> {
+        if read_only {
+            return self.deserialize_in_place(schema, bytes, SQLITE_DESERIALIZE_READONLY);
+        }
+
+        let snapshot = Connection::open(":memory:", false)?;
+        snapshot.deserialize_in_place("main", bytes, SQLITE_DESERIALIZE_RESIZEABLE)?;
+        snapshot.backup_to(self).with_schemas("main", schema).run()
+    }
+
+    fn deserialize_in_place(&self, schema: &str, bytes: &[u8], flags: i32) -> Result<()> {
+        let schema_name = CString::new(schema)?;
+        unsafe {
+            // Sqlite takes ownership of the copy and frees it when the schema is closed
+            let data = sqlite3_malloc64(bytes.len().max(1) as u64) as *mut u8;
+            if data.is_null() {
+                return Err(anyhow!(
+                    "Could not allocate {} bytes to deserialize",
+                    bytes.len()
+                ));
+            }
+            ptr::copy_nonoverlapping(bytes.as_ptr(), data, bytes.len());
+
+            let code = sqlite3_deserialize(
+                self.sqlite3,
+                schema_name.as_ptr(),
+                data,
+                bytes.len() as i64,
+                bytes.len() as i64,
+                (flags | SQLITE_DESERIALIZE_FREEONCLOSE) as u32,
+            );
+            if code != SQLITE_OK {
+                self.last_error()?;
+                return Err(anyhow!("Could not deserialize into schema {}", schema));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::connection::Connection;
+
+    #[test]
+    fn snapshots_load_into_shared_memory_databases() {
+        let source = Connection::open_memory("snapshots_load_source");
+        source
+            .exec("CREATE TABLE test (a TEXT); INSERT INTO test (a) VALUES ('snapshot')")
+            .unwrap();
+        let bytes = source.serialize("main").unwrap();
+
+        let destination = Connection::open_memory("snapshots_load_destination");
+        let other = Connection::open_memory("snapshots_load_destination");
+        destination.deserialize("main", &bytes, false).unwrap();
+
+        assert_eq!(
+            other
+                .prepare("SELECT a FROM test")
+                .unwrap()
+                .rows::<String>()
+                .unwrap(),
+            vec!["snapshot"]
+        );
+        destination
+            .exec("INSERT INTO test (a) VALUES ('writable')")
+            .unwrap();
+        assert!(destination
+            .deserialize("main", b"not a database", false)
+            .is_err());
+    }
+
+    #[test]
+    fn empty_databases_round_trip() {
+        let source = Connection::open_memory("empty_databases_round_trip_source");
+        let bytes = source.serialize("main").unwrap();
+        assert!(bytes.is_empty());
+
+        let destination = Connection::open_memory("empty_databases_round_trip_destination");
+        destination.exec("CREATE TABLE test (a TEXT)").unwrap();
+        destination.deserialize("main", &bytes, false).unwrap();
+        assert!(destination.exec("SELECT a FROM test").is_err());
+    }
+
+    #[test]
+    fn read_only_snapshots_reject_writes() {
+        let source = Connection::open_memory("read_only_snapshots_source");
+        source.exec("CREATE TABLE test (a TEXT)").unwrap();
+
+        let snapshot = Connection::open_memory("read_only_snapshots_snapshot");
+        snapshot
+            .deserialize("main", &source.serialize("main").unwrap(), true)
+            .unwrap();
+        snapshot.exec("SELECT a FROM test").unwrap();
+        assert!(snapshot.exec("INSERT INTO test (a) VALUES ('a')").is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use std::ffi::{c_int, CString};
 use std::marker::PhantomData;
+use std::ptr;
 use std::{slice, str};
 
 use anyhow::{anyhow, Context, Result};
@@ -34,7 +35,7 @@ pub enum SqlType {
 impl<'a> Statement<'a> {
     pub fn prepare<T: AsRef<str>>(connection: &'a Connection, query: T) -> Result<Self> {
         let mut statement = Self {
-            raw_statement: 0 as *mut _,
+            raw_statement: ptr::null_mut(),
             connection,
             phantom: PhantomData,
         };
@@ -45,7 +46,7 @@ impl<'a> Statement<'a> {
                 CString::new(query.as_ref())?.as_ptr(),
                 -1,
                 &mut statement.raw_statement,
-                0 as *mut _,
+                ptr::null_mut(),
             );
 
             connection.last_error().context("Prepare call failed.")?;
@@ -76,7 +77,7 @@ impl<'a> Statement<'a> {
         self.connection.last_error()
     }
 
-    pub fn column_blob<'b>(&'b mut self, index: i32) -> Result<&'b [u8]> {
+    pub fn column_blob(&mut self, index: i32) -> Result<&[u8]> {
         let index = index as c_int;
         let pointer = unsafe { sqlite3_column_blob(self.raw_statement, index) };
 
@@ -160,7 +161,7 @@ impl<'a> Statement<'a> {
         self.connection.last_error()
     }
 
-    pub fn column_text<'b>(&'b mut self, index: i32) -> Result<&'b str> {
+    pub fn column_text(&mut self, index: i32) -> Result<&str> {
         let index = index as c_int;
         let pointer = unsafe { sqlite3_column_text(self.raw_statement, index) };
 
@@ -171,7 +172,7 @@ impl<'a> Statement<'a> {
         let len = unsafe { sqlite3_column_bytes(self.raw_statement, index) as usize };
         self.connection.last_error()?;
 
-        let slice = unsafe { slice::from_raw_parts(pointer as *const u8, len) };
+        let slice = unsafe { slice::from_raw_parts(pointer, len) };
         Ok(str::from_utf8(slice)?)
     }
 
@@ -294,11 +295,11 @@ impl<'a> Statement<'a> {
 
 impl<'a> Drop for Statement<'a> {
     fn drop(&mut self) {
+        // Finalize only reports errors from the statement's most recent step, which have
+        // already been surfaced. Checking the connection's last error here would also pick up
+        // failures from unrelated calls made while this statement was alive
         unsafe {
             sqlite3_finalize(self.raw_statement);
-            self.connection
-                .last_error()
-                .expect("sqlite3 finalize failed for statement :(");
         };
     }
 }
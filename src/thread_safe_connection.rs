@@ -1,15 +1,73 @@
-use std::{ops::Deref, sync::Arc};
+use std::{
+    ops::Deref,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, OnceLock,
+    },
+    thread,
+    time::Duration,
+};
 
+use anyhow::{anyhow, Context, Result};
 use connection::Connection;
 use thread_local::ThreadLocal;
 
-use crate::connection;
+use crate::{connection, migrations::Migration, wal::CheckpointMode};
+
+pub(crate) type WriteJob = Box<dyn FnOnce(&ThreadSafeConnection) + Send>;
+type InitializeFn = dyn Fn(&Connection) -> Result<()> + Send + Sync;
+
+/// A step run against a freshly opened connection. Either a query, or an arbitrary
+/// function for things which can't be expressed as sql such as registering functions
+#[derive(Clone)]
+pub enum Initializer {
+    Query(&'static str),
+    Function(Arc<InitializeFn>),
+}
+
+impl Initializer {
+    pub fn function(function: impl Fn(&Connection) -> Result<()> + Send + Sync + 'static) -> Self {
+        Self::Function(Arc::new(function))
+    }
+
+    pub fn run(&self, connection: &Connection) -> Result<()> {
+        match self {
+            Self::Query(query) => connection
+                .exec(query)
+                .with_context(|| format!("Initialize query failed to execute: {}", query)),
+            Self::Function(function) => function(connection),
+        }
+    }
+}
+
+/// An initializer which runs once per database. Completion is tracked per step rather than
+/// per connection, so a step added to a clone of a connection which is already in use still
+/// runs, on the next connection handed out
+#[derive(Clone)]
+struct Setup {
+    initializer: Initializer,
+    complete: Arc<Mutex<bool>>,
+}
+
+/// Settings for the background checkpoint thread. The thread exits once every sender, and so
+/// every clone of the connection, has been dropped
+#[derive(Clone)]
+struct Checkpointer {
+    interval: Duration,
+    mode: CheckpointMode,
+    stop: Arc<OnceLock<Sender<()>>>,
+}
 
 pub struct ThreadSafeConnection {
     uri: Arc<str>,
     persistent: bool,
-    initialize_query: Option<&'static str>,
+    application_id: Option<i32>,
+    initializers: Vec<Initializer>,
+    setup: Vec<Setup>,
     connection: Arc<ThreadLocal<Connection>>,
+    write_queue: Option<Arc<OnceLock<Sender<WriteJob>>>>,
+    checkpointer: Option<Checkpointer>,
 }
 
 impl ThreadSafeConnection {
@@ -17,18 +75,249 @@ impl ThreadSafeConnection {
         Self {
             uri: Arc::from(uri),
             persistent,
-            initialize_query: None,
+            application_id: None,
+            initializers: Vec::new(),
+            setup: Vec::new(),
             connection: Default::default(),
+            write_queue: None,
+            checkpointer: None,
         }
     }
 
-    /// Sets the query to run every time a connection is opened. This must
-    /// be infallible (EG only use pragma statements)
-    pub fn with_initialize_query(mut self, initialize_query: &'static str) -> Self {
-        self.initialize_query = Some(initialize_query);
+    /// Adds a query to run every time a connection is opened
+    pub fn with_initialize_query(self, initialize_query: &'static str) -> Self {
+        self.with_initializer(Initializer::Query(initialize_query))
+    }
+
+    /// Adds an initializer to run every time a connection is opened. Initializers run
+    /// in the order they were added
+    pub fn with_initializer(mut self, initializer: Initializer) -> Self {
+        self.initializers.push(initializer);
+        self
+    }
+
+    /// Adds an initializer which runs once per database rather than once per connection,
+    /// such as running migrations. Setup runs before the next connection is handed out,
+    /// after the per connection initializers, and other threads wait for it to finish. This
+    /// holds even if the connection was already in use when the step was added. If setup
+    /// fails, it is attempted again the next time a connection is requested
+    pub fn with_setup(mut self, setup: Initializer) -> Self {
+        self.setup.push(Setup {
+            initializer: setup,
+            complete: Default::default(),
+        });
         self
     }
 
+    /// Adds migrations which run once per database before a connection is handed out on
+    /// any thread, ordered by their dependencies. Unless a migration has non transactional
+    /// steps, they run inside a single transaction so a failure leaves the database
+    /// untouched. Either way they wait for other connections migrating the same file to
+    /// finish first. The error is returned from `try_get` on every attempt until it succeeds
+    pub fn with_migrations(self, migrations: &'static [Migration]) -> Self {
+        self.with_setup(Initializer::function(move |connection| {
+            Migration::run_all_for_setup(connection, &migrations.iter().collect::<Vec<_>>())
+        }))
+    }
+
+    /// Refuses to open databases stamped with a different `PRAGMA application_id`, claiming
+    /// unmarked ones for this application. Once setup completes, `PRAGMA user_version` is
+    /// set to the number of migration steps applied
+    pub fn with_application_id(mut self, application_id: i32) -> Self {
+        self.application_id = Some(application_id);
+        self
+    }
+
+    /// Routes every call to `write` through a background thread which owns a single
+    /// connection. Funneling writes through one connection keeps the per thread connections
+    /// from fighting over the database write lock. Reads continue to use the thread local
+    /// connections. The thread is spawned on the first write and exits once every clone of
+    /// this connection has been dropped
+    pub fn with_write_queue(mut self) -> Self {
+        if self.write_queue.is_none() {
+            self.write_queue = Some(Default::default());
+        }
+        self
+    }
+
+    /// Checkpoints the database with the given mode every interval from a background thread.
+    /// The thread is spawned when the first connection is opened, and failed checkpoints
+    /// are retried on the next interval. Only useful for databases in WAL mode
+    pub fn with_checkpoint_interval(mut self, interval: Duration, mode: CheckpointMode) -> Self {
+        self.checkpointer = Some(Checkpointer {
+            interval,
+            mode,
+            stop: Default::default(),
+        });
+        self
+    }
+
+    /// Returns this thread's connection, opening and initializing it if needed. Unlike
+    /// deref, failures to initialize are returned rather than panicking
+    pub fn try_get(&self) -> Result<&Connection> {
+        let connection = self.connection.get_or_try(|| self.open_connection())?;
+        self.run_setup(connection)?;
+        Ok(connection)
+    }
+
+    /// Runs the callback against the write connection and returns a receiver for its result.
+    /// If no write queue was configured, the callback is run immediately on this thread's
+    /// connection instead
+    pub fn write<T, F>(&self, callback: F) -> Receiver<Result<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.queue_write(Box::new(move |connection| {
+            sender.send(connection.run_callback(callback)).ok();
+        }));
+        receiver
+    }
+
+    /// Runs a queued callback against this thread's connection. A panicking callback is
+    /// returned as an error, so it can't take down the thread running the queue, and any
+    /// transaction it left open is rolled back
+    pub(crate) fn run_callback<T>(
+        &self,
+        callback: impl FnOnce(&Connection) -> Result<T>,
+    ) -> Result<T> {
+        let connection = self.try_get()?;
+        panic::catch_unwind(AssertUnwindSafe(|| callback(connection))).unwrap_or_else(|panic| {
+            if connection.in_transaction() {
+                connection.exec("ROLLBACK").ok();
+            }
+
+            let message = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            Err(anyhow!("Database callback panicked: {}", message))
+        })
+    }
+
+    /// Runs the callback on a new thread with its own connection and returns a receiver for
+    /// its result. Meant for long running maintenance such as vacuuming, which would
+    /// otherwise block the calling thread or every write queued behind it
+    pub fn run_in_background<T, F>(&self, callback: F) -> Receiver<Result<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let connection = self.background_clone();
+        thread::Builder::new()
+            .name(format!("sqlez background: {}", self.uri))
+            .spawn(move || {
+                sender.send(connection.try_get().and_then(callback)).ok();
+            })
+            .expect("Could not spawn background thread");
+        receiver
+    }
+
+    /// Hands a job to the write queue thread, or runs it inline if there is no write queue
+    pub(crate) fn queue_write(&self, job: WriteJob) {
+        match &self.write_queue {
+            Some(write_queue) => write_queue
+                .get_or_init(|| self.spawn_writer())
+                .send(job)
+                .expect("Write queue thread exited unexpectedly"),
+            None => job(self),
+        }
+    }
+
+    fn spawn_writer(&self) -> Sender<WriteJob> {
+        let writer = self.background_clone();
+        let (sender, receiver) = mpsc::channel::<WriteJob>();
+
+        thread::Builder::new()
+            .name(format!("sqlez write queue: {}", self.uri))
+            .spawn(move || {
+                while let Ok(job) = receiver.recv() {
+                    job(&writer);
+                }
+            })
+            .expect("Could not spawn write queue thread");
+
+        sender
+    }
+
+    fn spawn_checkpointer(&self, interval: Duration, mode: CheckpointMode) -> Sender<()> {
+        let checkpointer = self.background_clone();
+        let (sender, receiver) = mpsc::channel::<()>();
+
+        thread::Builder::new()
+            .name(format!("sqlez checkpoint: {}", self.uri))
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                    checkpointer
+                        .try_get()
+                        .and_then(|connection| connection.checkpoint(mode))
+                        .ok();
+                }
+            })
+            .expect("Could not spawn checkpoint thread");
+
+        sender
+    }
+
+    /// A clone for background threads to use. It has no write queue or checkpointer so
+    /// that the threads don't keep themselves or each other alive
+    fn background_clone(&self) -> Self {
+        let mut connection = self.clone();
+        connection.write_queue = None;
+        connection.checkpointer = None;
+        connection
+    }
+
+    /// Opens a new db connection and runs the initializers on it. Falls back to a shared memory connection if the connection
+    /// isn't persistent or the file couldn't be opened
+    fn open_connection(&self) -> Result<Connection> {
+        let connection = if self.persistent {
+            self.open_file()
+        } else {
+            self.open_shared_memory()
+        };
+
+        if let Some(application_id) = self.application_id {
+            Migration::claim_application_id(&connection, application_id)?;
+        }
+
+        for initializer in &self.initializers {
+            initializer.run(&connection)?;
+        }
+
+        if let Some(checkpointer) = &self.checkpointer {
+            checkpointer
+                .stop
+                .get_or_init(|| self.spawn_checkpointer(checkpointer.interval, checkpointer.mode));
+        }
+
+        Ok(connection)
+    }
+
+    /// Runs any setup steps which haven't completed yet, in the order they were added
+    fn run_setup(&self, connection: &Connection) -> Result<()> {
+        let mut ran_setup = false;
+        for setup in &self.setup {
+            let mut complete = setup
+                .complete
+                .lock()
+                .map_err(|_| anyhow!("Setup lock poisoned"))?;
+            if !*complete {
+                setup.initializer.run(connection)?;
+                *complete = true;
+                ran_setup = true;
+            }
+        }
+
+        if ran_setup && self.application_id.is_some() {
+            Migration::update_user_version(connection)?;
+        }
+        Ok(())
+    }
+
     /// Opens a new db connection with the initialized file path. This is internal and only
     /// called from the deref function.
     /// If opening fails, the connection falls back to a shared memory connection
@@ -48,8 +337,12 @@ impl Clone for ThreadSafeConnection {
         Self {
             uri: self.uri.clone(),
             persistent: self.persistent,
-            initialize_query: self.initialize_query.clone(),
+            application_id: self.application_id,
+            initializers: self.initializers.clone(),
+            setup: self.setup.clone(),
             connection: self.connection.clone(),
+            write_queue: self.write_queue.clone(),
+            checkpointer: self.checkpointer.clone(),
         }
     }
 }
@@ -58,21 +351,417 @@ impl Deref for ThreadSafeConnection {
     type Target = Connection;
 
     fn deref(&self) -> &Self::Target {
-        self.connection.get_or(|| {
-            let connection = if self.persistent {
-                self.open_file()
-            } else {
-                self.open_shared_memory()
-            };
-
-            if let Some(initialize_query) = self.initialize_query {
-                connection.exec(initialize_query).expect(&format!(
-                    "Initialize query failed to execute: {}",
-                    initialize_query
-                ));
-            }
+        self.try_get()
+            .unwrap_or_else(|error| panic!("Could not open connection: {:?}", error))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use anyhow::Result;
+    use indoc::indoc;
+
+    use crate::{
+        connection::Connection,
+        migrations::{ChangedPolicy, Migration, Step},
+        test_support::TempDir,
+        thread_safe_connection::{Initializer, ThreadSafeConnection},
+        wal::CheckpointMode,
+    };
+
+    #[test]
+    fn writes_from_many_threads_are_queued() {
+        let connection = ThreadSafeConnection::new("writes_from_many_threads_are_queued", false)
+            .with_write_queue();
+
+        connection
+            .write(|connection| {
+                connection.exec(indoc! {"
+                    CREATE TABLE test (
+                        value INTEGER
+                    );"})
+            })
+            .recv()
+            .unwrap()
+            .unwrap();
+
+        let handles = (0..8)
+            .map(|i| {
+                let connection = connection.clone();
+                thread::spawn(move || {
+                    connection
+                        .write(move |connection| {
+                            connection
+                                .prepare("INSERT INTO test (value) VALUES (?)")?
+                                .bound(i)?
+                                .run()
+                        })
+                        .recv()
+                        .unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+
+        let mut values = connection
+            .prepare("SELECT value FROM test")
+            .unwrap()
+            .rows::<i32>()
+            .unwrap();
+        values.sort();
+        assert_eq!(values, (0..8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn panicking_writes_are_returned_as_errors() {
+        let connection =
+            ThreadSafeConnection::new("panicking_writes_are_returned", false).with_write_queue();
+        connection
+            .write(|connection| connection.exec("CREATE TABLE test (value INTEGER)"))
+            .recv()
+            .unwrap()
+            .unwrap();
+
+        let error = connection
+            .write(|connection| -> Result<()> {
+                connection.exec("BEGIN; INSERT INTO test (value) VALUES (1)")?;
+                panic!("write failed")
+            })
+            .recv()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Database callback panicked: write failed"
+        );
 
+        // The writer survives the panic, and the panicking write was rolled back
+        let count = connection
+            .clone()
+            .write(|connection| {
+                connection
+                    .prepare("SELECT COUNT(*) FROM test")?
+                    .row::<usize>()
+            })
+            .recv()
+            .unwrap()
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn write_without_queue_runs_inline() {
+        let connection = ThreadSafeConnection::new("write_without_queue_runs_inline", false);
+
+        let result = connection
+            .write(|connection| connection.prepare("SELECT 1")?.row::<i32>())
+            .recv()
+            .unwrap()
+            .unwrap();
+        assert_eq!(result, 1);
+    }
+
+    #[test]
+    fn failing_initializer_is_returned_from_try_get() {
+        let connection = ThreadSafeConnection::new("failing_initializer", false)
+            .with_initialize_query("PRAGMA foreign_keys = ON")
+            .with_initializer(Initializer::function(|_| {
+                anyhow::bail!("Initializer failed")
+            }));
+
+        assert!(connection.try_get().is_err());
+    }
+
+    #[test]
+    fn setup_runs_once_per_database() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let connection = ThreadSafeConnection::new("setup_runs_once_per_database", false)
+            .with_setup(Initializer::function({
+                let runs = runs.clone();
+                move |connection| {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    connection.exec("CREATE TABLE test (value INTEGER)")
+                }
+            }));
+
+        let handles = (0..4)
+            .map(|_| {
+                let connection = connection.clone();
+                thread::spawn(move || {
+                    connection
+                        .try_get()?
+                        .prepare("SELECT COUNT(*) FROM test")?
+                        .row::<i32>()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap().unwrap(), 0);
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn setup_added_after_first_use_still_runs() {
+        let connection = ThreadSafeConnection::new("setup_added_after_first_use", false);
+        connection
+            .exec("CREATE TABLE first (value INTEGER)")
+            .unwrap();
+
+        let with_setup = connection
+            .clone()
+            .with_setup(Initializer::Query("CREATE TABLE second (value INTEGER)"));
+        with_setup.try_get().unwrap();
+        connection.exec("SELECT * FROM second").unwrap();
+    }
+
+    #[test]
+    fn migrations_run_before_connections_are_handed_out() {
+        static MIGRATIONS: &[Migration] = &[Migration::new(
+            "test",
+            &["CREATE TABLE test (value INTEGER)"],
+        )];
+
+        let connection = ThreadSafeConnection::new("migrations_run_on_setup", false)
+            .with_migrations(MIGRATIONS)
+            .with_write_queue();
+
+        connection
+            .write(|connection| {
+                connection
+                    .prepare("INSERT INTO test (value) VALUES (?)")?
+                    .bound(1)?
+                    .run()
+            })
+            .recv()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
             connection
-        })
+                .try_get()
+                .unwrap()
+                .prepare("SELECT value FROM test")
+                .unwrap()
+                .rows::<i32>()
+                .unwrap(),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn failed_migrations_are_rolled_back_and_reported() {
+        static MIGRATIONS: &[Migration] = &[Migration::new(
+            "test",
+            &[
+                "CREATE TABLE test (value INTEGER)",
+                "INSERT INTO missing (value) VALUES (1)",
+            ],
+        )];
+
+        let connection = ThreadSafeConnection::new("failed_migrations_are_rolled_back", false)
+            .with_migrations(MIGRATIONS);
+        assert!(connection.try_get().is_err());
+
+        let unmigrated = ThreadSafeConnection::new("failed_migrations_are_rolled_back", false);
+        assert_eq!(
+            unmigrated
+                .prepare("SELECT COUNT(*) FROM sqlite_master WHERE name = 'test'")
+                .unwrap()
+                .row::<i32>()
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn changed_migrations_on_files_are_backed_up_during_setup() {
+        static OLD: &[Migration] = &[Migration::new("test", &["CREATE TABLE test (a TEXT)"])];
+        static NEW: &[Migration] = &[Migration::new("test", &["CREATE TABLE test (b TEXT)"])
+            .with_changed_policy(ChangedPolicy::BackupAndRecreate)];
+
+        let directory = TempDir::new("setup_recovery");
+        let path = directory.join("db.sqlite").to_string_lossy().into_owned();
+        ThreadSafeConnection::new(&path, true)
+            .with_migrations(OLD)
+            .try_get()
+            .unwrap();
+
+        let started = Instant::now();
+        let connection = ThreadSafeConnection::new(&path, true).with_migrations(NEW);
+        connection
+            .try_get()
+            .unwrap()
+            .exec("SELECT b FROM test")
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(fs::read_dir(directory.path()).unwrap().any(|entry| {
+            entry
+                .unwrap()
+                .path()
+                .extension()
+                .is_some_and(|extension| extension == "bak")
+        }));
+    }
+
+    #[test]
+    fn connections_opened_together_wait_for_each_others_migrations() {
+        fn slow_step(connection: &Connection) -> Result<()> {
+            thread::sleep(Duration::from_millis(20));
+            connection.exec("CREATE TABLE slow (value INTEGER)")
+        }
+
+        static STEPS: &[Step] = &[
+            Step::Sql("CREATE TABLE test (value INTEGER)"),
+            Step::Function {
+                name: "slow_step",
+                version: 1,
+                function: slow_step,
+            },
+        ];
+        static MIGRATIONS: &[Migration] = &[Migration::with_steps("test", STEPS)];
+
+        let directory = TempDir::new("connections_opened_together");
+        let path = directory.join("db.sqlite").to_string_lossy().into_owned();
+
+        let handles = (0..4)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    ThreadSafeConnection::new(&path, true)
+                        .with_migrations(MIGRATIONS)
+                        .try_get()?
+                        .prepare("SELECT COUNT(*) FROM migrations")?
+                        .row::<usize>()
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap().unwrap(), 2);
+        }
+    }
+
+    #[test]
+    fn non_transactional_migrations_apply_once_across_connections() {
+        fn slow_step(connection: &Connection) -> Result<()> {
+            thread::sleep(Duration::from_millis(20));
+            connection.exec("CREATE TABLE slow (value INTEGER)")
+        }
+
+        static STEPS: &[Step] = &[
+            Step::Function {
+                name: "slow_step",
+                version: 1,
+                function: slow_step,
+            },
+            Step::Sql("VACUUM"),
+            Step::Sql("CREATE TABLE test (value INTEGER)"),
+        ];
+        static MIGRATIONS: &[Migration] =
+            &[Migration::with_steps("test", STEPS).with_non_transactional_steps(&[1])];
+
+        let directory = TempDir::new("non_transactional_migrations");
+        let path = directory.join("db.sqlite").to_string_lossy().into_owned();
+
+        let handles = (0..2)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    ThreadSafeConnection::new(&path, true)
+                        .with_migrations(MIGRATIONS)
+                        .try_get()?
+                        .prepare("SELECT step FROM migrations ORDER BY step")?
+                        .rows::<usize>()
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap().unwrap(), vec![0, 1, 2]);
+        }
+    }
+
+    #[test]
+    fn application_id_is_claimed_and_checked() {
+        static MIGRATIONS: &[Migration] = &[Migration::new(
+            "test",
+            &[
+                "CREATE TABLE test (value INTEGER)",
+                "CREATE TABLE other (value INTEGER)",
+            ],
+        )];
+
+        let connection = ThreadSafeConnection::new("application_id_is_claimed", false)
+            .with_application_id(42)
+            .with_migrations(MIGRATIONS);
+        assert_eq!(
+            connection
+                .try_get()
+                .unwrap()
+                .prepare("SELECT * FROM pragma_application_id, pragma_user_version")
+                .unwrap()
+                .row::<(i32, i32)>()
+                .unwrap(),
+            (42, 2)
+        );
+
+        let foreign =
+            ThreadSafeConnection::new("application_id_is_claimed", false).with_application_id(7);
+        assert!(foreign.try_get().is_err());
+    }
+
+    #[test]
+    fn checkpoints_run_in_the_background() {
+        let directory = TempDir::new("background_checkpoint");
+        let path = directory.join("db.sqlite");
+
+        let connection = ThreadSafeConnection::new(path.to_str().unwrap(), true)
+            .with_initialize_query("PRAGMA journal_mode = WAL")
+            .with_checkpoint_interval(Duration::from_millis(10), CheckpointMode::Truncate);
+        connection
+            .exec("CREATE TABLE test (value INTEGER); INSERT INTO test (value) VALUES (1)")
+            .unwrap();
+
+        let wal_path = directory.join("db.sqlite-wal");
+        let started = Instant::now();
+        while fs::metadata(&wal_path).unwrap().len() > 0 {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        drop(connection);
+    }
+
+    #[test]
+    fn vacuum_runs_in_the_background() {
+        let connection = ThreadSafeConnection::new("vacuum_runs_in_the_background", false);
+        connection
+            .exec(indoc! {"
+                CREATE TABLE test (value TEXT);
+                WITH RECURSIVE numbers(n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM numbers LIMIT 100)
+                INSERT INTO test (value) SELECT printf('%.2000d', n) FROM numbers;
+                DELETE FROM test;"})
+            .unwrap();
+
+        let report = connection
+            .run_in_background(|connection| connection.vacuum())
+            .recv()
+            .unwrap()
+            .unwrap();
+        assert!(report.freed_pages > 0);
+        assert_eq!(connection.freelist_count().unwrap(), 0);
     }
 }
@@ -0,0 +1,164 @@
+// Reclaiming space from databases which have grown. A full vacuum rebuilds the database in
+// place, `vacuum_into` writes a compacted copy to a new file, and incremental vacuums free a
+// number of pages at a time for databases with `auto_vacuum = INCREMENTAL`. Each reports the
+// pages freed and the size before and after. Vacuums can take a while on large databases, so
+// ThreadSafeConnection can run them on a background thread with `run_in_background`.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+use crate::{connection::Connection, pragma::AutoVacuum};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VacuumReport {
+    pub freed_pages: usize,
+    /// Size of the database in bytes before vacuuming
+    pub size_before: u64,
+    /// Size in bytes of the vacuumed database, or of the new file for `vacuum_into`
+    pub size_after: u64,
+}
+
+impl Connection {
+    /// Rebuilds the main database, releasing every free page. Can't run inside a transaction
+    pub fn vacuum(&self) -> Result<VacuumReport> {
+        self.measure_vacuum(|connection| connection.exec("VACUUM"))
+    }
+
+    /// Writes a compacted copy of the main database to a new file, leaving this database
+    /// untouched. Errors if the file already exists
+    pub fn vacuum_into(&self, path: impl AsRef<Path>) -> Result<VacuumReport> {
+        let path = path.as_ref();
+        let path = path
+            .to_str()
+            .ok_or_else(|| anyhow!("Path {} is not valid unicode", path.display()))?;
+
+        let (page_count, page_size) = (self.page_count()?, self.page_size()?);
+        self.prepare("VACUUM INTO CAST(? AS TEXT)")?
+            .bound(path)?
+            .run()?;
+        let copy = Connection::open(path, true)?;
+        let copy_page_count = copy.page_count()?;
+
+        Ok(VacuumReport {
+            freed_pages: page_count.saturating_sub(copy_page_count),
+            size_before: (page_count * page_size) as u64,
+            size_after: (copy_page_count * copy.page_size()?) as u64,
+        })
+    }
+
+    /// Frees up to `pages` free pages, or all of them if None. Requires the database to be
+    /// in incremental auto vacuum mode
+    pub fn incremental_vacuum(&self, pages: Option<usize>) -> Result<VacuumReport> {
+        let auto_vacuum = self.auto_vacuum()?;
+        if auto_vacuum != AutoVacuum::Incremental {
+            return Err(anyhow!(
+                "Incremental vacuum requires auto_vacuum = INCREMENTAL, but it is {}",
+                auto_vacuum
+            ));
+        }
+
+        self.measure_vacuum(|connection| match pages {
+            Some(pages) => connection.exec(format!("PRAGMA incremental_vacuum({})", pages)),
+            None => connection.exec("PRAGMA incremental_vacuum"),
+        })
+    }
+
+    /// Sets the auto vacuum mode, running a full vacuum if the database already has tables
+    /// and the change can't otherwise take effect
+    pub fn set_auto_vacuum(&self, auto_vacuum: AutoVacuum) -> Result<()> {
+        self.exec(format!("PRAGMA auto_vacuum = {}", auto_vacuum))?;
+        if self.auto_vacuum()? != auto_vacuum {
+            self.vacuum()?;
+        }
+
+        let applied = self.auto_vacuum()?;
+        if applied != auto_vacuum {
+            return Err(anyhow!(
+                "Sqlite ignored PRAGMA auto_vacuum = {}, the value is still {}",
+                auto_vacuum,
+                applied
+            ));
+        }
+        Ok(())
+    }
+
+    fn measure_vacuum(
+        &self,
+        vacuum: impl FnOnce(&Connection) -> Result<()>,
+    ) -> Result<VacuumReport> {
+        let page_size = self.page_size()?;
+        let page_count_before = self.page_count()?;
+        vacuum(self)?;
+        let page_count_after = self.page_count()?;
+
+        Ok(VacuumReport {
+            freed_pages: page_count_before.saturating_sub(page_count_after),
+            size_before: (page_count_before * page_size) as u64,
+            size_after: (page_count_after * page_size) as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use indoc::indoc;
+
+    use crate::{connection::Connection, pragma::AutoVacuum, test_support::TempDir};
+
+    fn fill_and_delete(connection: &Connection) {
+        connection
+            .exec(indoc! {"
+                CREATE TABLE IF NOT EXISTS test (value TEXT);
+                WITH RECURSIVE numbers(n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM numbers LIMIT 200)
+                INSERT INTO test (value) SELECT printf('%.2000d', n) FROM numbers;
+                DELETE FROM test;"})
+            .unwrap();
+    }
+
+    #[test]
+    fn vacuum_reports_freed_pages() {
+        let connection = Connection::open_memory("vacuum_reports_freed_pages");
+        fill_and_delete(&connection);
+        let free_pages = connection.freelist_count().unwrap();
+        assert!(free_pages > 0);
+
+        let report = connection.vacuum().unwrap();
+        assert_eq!(report.freed_pages, free_pages);
+        assert!(report.size_after < report.size_before);
+        assert_eq!(connection.freelist_count().unwrap(), 0);
+
+        let directory = TempDir::new("vacuum_into");
+        let path = directory.join("copy.sqlite");
+        let source = Connection::open_file(directory.join("source.sqlite").to_str().unwrap());
+        fill_and_delete(&source);
+        let report = source.vacuum_into(&path).unwrap();
+        assert!(report.freed_pages > 0);
+        assert_eq!(fs::metadata(&path).unwrap().len(), report.size_after);
+        assert!(source.vacuum_into(&path).is_err());
+
+        drop(source);
+    }
+
+    #[test]
+    fn incremental_vacuum_frees_pages_in_steps() {
+        let connection = Connection::open_memory("incremental_vacuum_frees_pages_in_steps");
+        assert!(connection.incremental_vacuum(None).is_err());
+
+        fill_and_delete(&connection);
+        connection.set_auto_vacuum(AutoVacuum::Incremental).unwrap();
+        fill_and_delete(&connection);
+        let free_pages = connection.freelist_count().unwrap();
+
+        assert_eq!(
+            connection.incremental_vacuum(Some(2)).unwrap().freed_pages,
+            2
+        );
+        assert_eq!(
+            connection.incremental_vacuum(None).unwrap().freed_pages,
+            free_pages - 2
+        );
+    }
+}
@@ -0,0 +1,160 @@
+// Control over the write ahead log for databases in WAL mode. Checkpoints copy frames from
+// the log back into the database file, and the WAL hook reports every commit along with the
+// size of the log so applications can decide when to checkpoint themselves.
+// ThreadSafeConnection can also checkpoint periodically from a background thread, see
+// `ThreadSafeConnection::with_checkpoint_interval`.
+
+use std::{
+    ffi::{c_char, c_int, c_void, CStr},
+    ptr,
+};
+
+use anyhow::Result;
+use libsqlite3_sys::*;
+
+use crate::connection::Connection;
+
+pub(crate) type WalHook = dyn FnMut(&str, usize) + Send;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckpointMode {
+    /// Checkpoint as many frames as possible without waiting for readers or writers
+    Passive,
+    /// Wait for writers to finish, then checkpoint every frame
+    Full,
+    /// Like Full, then wait for readers so the next writer starts the log from the beginning
+    Restart,
+    /// Like Restart, then truncate the log file to zero bytes
+    Truncate,
+}
+
+impl CheckpointMode {
+    fn sqlite_mode(&self) -> c_int {
+        match self {
+            CheckpointMode::Passive => SQLITE_CHECKPOINT_PASSIVE,
+            CheckpointMode::Full => SQLITE_CHECKPOINT_FULL,
+            CheckpointMode::Restart => SQLITE_CHECKPOINT_RESTART,
+            CheckpointMode::Truncate => SQLITE_CHECKPOINT_TRUNCATE,
+        }
+    }
+}
+
+/// Frame counts reported by a checkpoint. Both are -1 if the database isn't in WAL mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Frames in the log after the checkpoint
+    pub log_frames: i32,
+    /// Frames in the log which have been copied back into the database
+    pub checkpointed_frames: i32,
+}
+
+impl Connection {
+    /// Checkpoints every attached database. Modes other than Passive error if another
+    /// connection kept the checkpoint from completing
+    pub fn checkpoint(&self, mode: CheckpointMode) -> Result<Checkpoint> {
+        let mut log_frames = 0;
+        let mut checkpointed_frames = 0;
+        unsafe {
+            sqlite3_wal_checkpoint_v2(
+                self.sqlite3,
+                ptr::null(),
+                mode.sqlite_mode(),
+                &mut log_frames,
+                &mut checkpointed_frames,
+            );
+            self.last_error()?;
+        }
+
+        Ok(Checkpoint {
+            log_frames,
+            checkpointed_frames,
+        })
+    }
+
+    /// Calls the hook after every commit to a database in WAL mode with the schema name and
+    /// the number of frames in its log. Registering a hook replaces sqlite's automatic
+    /// checkpointing, so the hook becomes responsible for keeping the log from growing
+    pub fn set_wal_hook(&self, hook: impl FnMut(&str, usize) + Send + 'static) {
+        let mut hook: Box<Box<WalHook>> = Box::new(Box::new(hook));
+        unsafe {
+            sqlite3_wal_hook(
+                self.sqlite3,
+                Some(call_wal_hook),
+                &mut *hook as *mut Box<WalHook> as *mut c_void,
+            );
+        }
+        // The previous hook is dropped only once sqlite no longer points to it
+        self.wal_hook.replace(Some(hook));
+    }
+}
+
+extern "C" fn call_wal_hook(
+    hook: *mut c_void,
+    _sqlite3: *mut sqlite3,
+    schema: *const c_char,
+    frames: c_int,
+) -> c_int {
+    unsafe {
+        let hook = &mut *(hook as *mut Box<WalHook>);
+        let schema = CStr::from_ptr(schema).to_string_lossy();
+        hook(&schema, frames.max(0) as usize);
+    }
+    SQLITE_OK
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::{
+        connection::Connection,
+        pragma::JournalMode,
+        test_support::TempDir,
+        wal::{Checkpoint, CheckpointMode},
+    };
+
+    #[test]
+    fn checkpoints_report_frames_and_hook_sees_commits() {
+        let directory = TempDir::new("checkpoints");
+        let path = directory.join("db.sqlite");
+        let connection = Connection::open_file(path.to_str().unwrap());
+        connection.set_journal_mode(JournalMode::Wal).unwrap();
+
+        let commits = Arc::new(Mutex::new(Vec::new()));
+        connection.set_wal_hook({
+            let commits = commits.clone();
+            move |schema, frames| commits.lock().unwrap().push((schema.to_string(), frames))
+        });
+
+        connection.exec("CREATE TABLE test (a TEXT)").unwrap();
+        connection
+            .exec("INSERT INTO test (a) VALUES ('row')")
+            .unwrap();
+
+        let commits = commits.lock().unwrap().clone();
+        assert_eq!(commits.len(), 2);
+        assert_eq!(commits[0].0, "main");
+        assert!(commits[1].1 > commits[0].1);
+
+        let checkpoint = connection.checkpoint(CheckpointMode::Passive).unwrap();
+        assert_eq!(checkpoint.log_frames, commits[1].1 as i32);
+        assert_eq!(checkpoint.checkpointed_frames, checkpoint.log_frames);
+
+        assert_eq!(
+            connection.checkpoint(CheckpointMode::Truncate).unwrap(),
+            Checkpoint {
+                log_frames: 0,
+                checkpointed_frames: 0
+            }
+        );
+        assert_eq!(
+            fs::metadata(directory.join("db.sqlite-wal")).unwrap().len(),
+            0
+        );
+
+        drop(connection);
+    }
+}
@@ -0,0 +1,4 @@
+CREATE TABLE workspaces (
+    workspace_id INTEGER PRIMARY KEY,
+    path TEXT
+);
@@ -0,0 +1,2 @@
+-- Track when each workspace was last opened
+ALTER TABLE workspaces ADD COLUMN timestamp TEXT;